@@ -8,7 +8,7 @@ include!("src/cli.rs");
 
 fn main() -> Result<(), Error> {
     let outdir = match env::var_os("OUT_DIR") {
-        None => return Err(Error::new(std::io::ErrorKind::Other, "no $OUT_DIR!")),
+        None => return Err(Error::other("no $OUT_DIR!")),
         Some(outdir) => outdir,
     };
     let mut app = ProgramOptions::command();
@@ -40,7 +40,7 @@ fn main() -> Result<(), Error> {
 
 fn get_commit_hash() -> Option<String> {
     process::Command::new("git")
-        .args(&["rev-parse", "--short", "HEAD"])
+        .args(["rev-parse", "--short", "HEAD"])
         .output()
         .ok()
         .and_then(|r| {
@@ -48,6 +48,6 @@ class DeleteThumbnailsAction(GObject.GObject, Nautilus.MenuProvider):
             return []
 
     def _run_thumbs(self, _menu, paths):
-        cmd = ['thumbs', 'delete', '-r', '-f'] + paths
+        cmd = ['thumbs', 'delete', '-r', '-f', '--notify'] + paths
         Gio.Subprocess.new(cmd, Gio.SubprocessFlags.NONE)
 
@@ -0,0 +1,347 @@
+use crate::journal::JournalAction;
+use crate::sandbox::thumbnail_app;
+use crate::{png, read_metadata, Error, Thumbnail, UnThumbnailer};
+use anyhow::{anyhow, format_err, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, metadata, File};
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Name of the index of a backup archive, stored before the thumbnails.
+const INDEX_NAME: &str = "index.json";
+
+/// Directories of a thumbnail cache, under its cache directory.
+const SIZE_DIRS: [&str; 5] = ["normal", "large", "x-large", "xx-large", "fail"];
+
+/// A thumbnail in the index of a backup archive.
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    /// Path of the thumbnail in the archive, which is also its path relative to
+    /// the cache directory for the thumbnails of the main cache
+    path: PathBuf,
+    uri: String,
+    mtime: u64,
+    /// Where the thumbnails of the other caches (of sandboxed applications, or the
+    /// legacy one) go back to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache: Option<OtherCache>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OtherCache {
+    dir: PathBuf,
+    /// Path of the thumbnail, relative to `dir`
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreResults {
+    pub restored: Vec<Thumbnail>,
+    /// Thumbnails whose file is missing or was modified since the backup.
+    pub stale: u32,
+    /// Thumbnails that are already in the cache.
+    pub existing: u32,
+    /// Thumbnails of the caches of sandboxed applications (or of the legacy
+    /// cache) that aren't used anymore.
+    pub unknown_cache: u32,
+}
+
+impl UnThumbnailer {
+    /// Archive the thumbnails of local files to the tar archive at `archive`,
+    /// along with an index of their original files.
+    ///
+    /// The thumbnails of the caches of sandboxed applications and of the legacy
+    /// cache are archived under `caches/N`, with the path of their cache.
+    pub fn backup(&self, archive: &Path) -> Result<Vec<Thumbnail>, Error> {
+        let mut index = Vec::new();
+        let mut thumbs = Vec::new();
+        let mut other_caches = Vec::new();
+        for entry in self.scan() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    debug!("{}", e);
+                    continue;
+                }
+            };
+            let path = entry.thumbnail();
+            let root = match path.parent().and_then(cache_root) {
+                Some(r) if r != self.cache_dir => {
+                    if !other_caches.contains(&r) {
+                        other_caches.push(r.clone());
+                    }
+                    Some(r)
+                }
+                _ => None,
+            };
+            match self.index_entry(path, root.as_deref(), &other_caches) {
+                Ok((e, file)) => {
+                    index.push(e);
+                    thumbs.push(Thumbnail {
+                        thumbnail: path.to_path_buf(),
+                        file,
+                        app: entry.app().map(Into::into),
+                    });
+                }
+                Err(e) => debug!("{} for {}", e, path.to_string_lossy()),
+            }
+        }
+
+        let file = File::create(archive)
+            .with_context(|| format!("Failed to create {}", archive.to_string_lossy()))?;
+        let mut builder = tar::Builder::new(file);
+
+        let data = serde_json::to_vec_pretty(&index)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+        builder.append_data(&mut header, INDEX_NAME, data.as_slice())?;
+
+        for (entry, thumb) in index.iter().zip(&thumbs) {
+            info!(
+                "Archiving a thumbnail for '{}'",
+                thumb.file.to_string_lossy()
+            );
+            builder.append_path_with_name(&thumb.thumbnail, &entry.path)?;
+        }
+        builder.into_inner()?;
+
+        Ok(thumbs)
+    }
+
+    /// The index entry of the thumbnail at `path`, in the cache `root` if it isn't
+    /// the main one, numbered after its place in `other_caches`.
+    fn index_entry(
+        &self,
+        path: &Path,
+        root: Option<&Path>,
+        other_caches: &[PathBuf],
+    ) -> Result<(IndexEntry, PathBuf)> {
+        let meta = read_metadata(BufReader::new(File::open(path)?))?;
+        let uri = meta
+            .uri
+            .ok_or_else(|| anyhow!("failed to find origin path"))?;
+        let mtime = meta
+            .mtime
+            .ok_or_else(|| anyhow!("failed to find origin modification time"))?;
+        let url = Url::parse(&uri).map_err(|s| format_err!("{}", s))?;
+        let file = url
+            .to_file_path()
+            .map_err(|_| anyhow!("ignoring origin URI with scheme {}", url.scheme()))?;
+
+        let entry = match root {
+            Some(root) => {
+                let n = other_caches.iter().position(|c| c == root).unwrap_or(0);
+                let relative = path.strip_prefix(root)?;
+                IndexEntry {
+                    path: Path::new("caches").join(n.to_string()).join(relative),
+                    uri,
+                    mtime,
+                    cache: Some(OtherCache {
+                        dir: root.to_path_buf(),
+                        path: relative.to_path_buf(),
+                    }),
+                }
+            }
+            None => IndexEntry {
+                path: path.strip_prefix(&self.cache_dir)?.to_path_buf(),
+                uri,
+                mtime,
+                cache: None,
+            },
+        };
+        Ok((entry, file))
+    }
+
+    /// Restore the thumbnails from the backup archive at `archive` to the cache.
+    ///
+    /// Only thumbnails whose file still exists with the same modification time
+    /// are restored, and existing thumbnails are never overwritten. The
+    /// thumbnails of other caches are only restored if that cache is used.
+    pub fn restore(&self, archive: &Path) -> Result<RestoreResults, Error> {
+        let file = File::open(archive)
+            .with_context(|| format!("Failed to open {}", archive.to_string_lossy()))?;
+        let mut archive = tar::Archive::new(file);
+
+        let mut results = RestoreResults::default();
+        let mut index: Option<HashMap<PathBuf, IndexEntry>> = None;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+
+            if path == Path::new(INDEX_NAME) {
+                let entries: Vec<IndexEntry> =
+                    serde_json::from_reader(&mut entry).context("Invalid backup index")?;
+                index = Some(entries.into_iter().map(|e| (e.path.clone(), e)).collect());
+                continue;
+            }
+
+            let index = index
+                .as_ref()
+                .ok_or_else(|| anyhow!("Invalid backup archive: missing index"))?;
+            let meta = match index.get(&path) {
+                Some(m) if is_relative(&path) => m,
+                _ => {
+                    debug!("Ignoring unknown entry {}", path.to_string_lossy());
+                    continue;
+                }
+            };
+
+            let file = Url::parse(&meta.uri)
+                .ok()
+                .and_then(|u| u.to_file_path().ok())
+                .ok_or_else(|| anyhow!("Invalid backup index: bad URI {}", meta.uri))?;
+            if !is_unmodified(&file, meta.mtime) {
+                debug!(
+                    "Skipping a stale thumbnail for '{}'",
+                    file.to_string_lossy()
+                );
+                results.stale += 1;
+                continue;
+            }
+
+            let dest = match &meta.cache {
+                None => self.cache_dir.join(&path),
+                Some(other) if !is_relative(&other.path) => {
+                    debug!("Ignoring unknown entry {}", path.to_string_lossy());
+                    continue;
+                }
+                Some(other) if self.uses_cache(&other.dir) => other.dir.join(&other.path),
+                Some(other) => {
+                    debug!(
+                        "Skipping a thumbnail of the unused cache {}",
+                        other.dir.to_string_lossy()
+                    );
+                    results.unknown_cache += 1;
+                    continue;
+                }
+            };
+            if dest.exists() {
+                debug!(
+                    "Skipping an existing thumbnail for '{}'",
+                    file.to_string_lossy()
+                );
+                results.existing += 1;
+                continue;
+            }
+
+            info!("Restoring a thumbnail for '{}'", file.to_string_lossy());
+            let restored = restore_entry(&mut entry, &dest);
+            self.record(JournalAction::Restore, &dest, Some(&file), None, &restored);
+            restored?;
+
+            results.restored.push(Thumbnail {
+                app: thumbnail_app(&dest),
+                thumbnail: dest,
+                file,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Whether the cache directory `dir` is one of the caches used.
+    fn uses_cache(&self, dir: &Path) -> bool {
+        self.app_caches.iter().any(|c| c.dir == dir)
+            || self
+                .cache_locs
+                .iter()
+                .any(|l| cache_root(l).is_some_and(|r| r == dir))
+    }
+}
+
+/// The cache directory of the directory `location` of a thumbnail cache.
+fn cache_root(location: &Path) -> Option<PathBuf> {
+    location
+        .ancestors()
+        .find(|a| {
+            a.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| SIZE_DIRS.contains(&n))
+        })
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+}
+
+/// Write the thumbnail in the archive entry `entry` to `dest`.
+fn restore_entry<R: Read>(entry: &mut R, dest: &Path) -> Result<()> {
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    if let Some(parent) = dest.parent() {
+        create_dir_all(parent)?;
+    }
+    png::write_thumbnail(dest, &data)
+}
+
+/// Whether `path` is relative and stays below the directory it is relative to.
+fn is_relative(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Check that the file at `path` exists and was last modified at `mtime`.
+pub(crate) fn is_unmodified(path: &Path, mtime: u64) -> bool {
+    metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|t| t.as_secs() == mtime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{enable_journal, journal_actions, thumbnailed_file, TempDir};
+    use std::fs::{read, remove_file};
+    use std::time::Duration;
+
+    #[test]
+    fn backup_and_restore() {
+        let dir = TempDir::new("backup-restore");
+        let cache = dir.path().join("cache");
+        let kept = dir.path().join("kept");
+        let modified = dir.path().join("modified");
+        let kept_thumb = thumbnailed_file(&cache, &kept);
+        let modified_thumb = thumbnailed_file(&cache, &modified);
+        let data = read(&kept_thumb).unwrap();
+        let archive = dir.path().join("backup.tar");
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        enable_journal(&mut un, dir.path());
+
+        assert_eq!(un.backup(&archive).unwrap().len(), 2);
+
+        remove_file(&kept_thumb).unwrap();
+        remove_file(&modified_thumb).unwrap();
+        File::options()
+            .write(true)
+            .open(&modified)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let results = un.restore(&archive).unwrap();
+        assert_eq!(results.restored.len(), 1);
+        assert_eq!(results.stale, 1);
+        assert_eq!(read(&kept_thumb).unwrap(), data);
+        assert!(!modified_thumb.exists());
+        assert_eq!(
+            journal_actions(&un),
+            [(JournalAction::Restore, "ok".to_string())]
+        );
+
+        // existing thumbnails are never overwritten
+        let results = un.restore(&archive).unwrap();
+        assert!(results.restored.is_empty());
+        assert_eq!(results.existing, 1);
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert!(is_relative(Path::new("normal/a.png")));
+        assert!(!is_relative(Path::new("../normal/a.png")));
+        assert!(!is_relative(Path::new("/normal/a.png")));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{Error, UnThumbnailer};
+use anyhow::{Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, remove_file, rename, write, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// The position of an interrupted cleanup.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Checkpoint {
+    /// The thumbnail cache that was cleaned up
+    cache_dir: PathBuf,
+    /// The last cache entry processed
+    resume_after: PathBuf,
+}
+
+/// The checkpoint file, in `$XDG_STATE_HOME/thumbs/cleanup-checkpoint.json`.
+fn checkpoint_path() -> Result<PathBuf> {
+    let mut path = dirs::state_dir().ok_or(Error::NoDirectory("state"))?;
+    path.push("thumbs/cleanup-checkpoint.json");
+    Ok(path)
+}
+
+impl UnThumbnailer {
+    /// Save the position of an interrupted cleanup of this cache, its
+    /// `resume_after` result, in `$XDG_STATE_HOME/thumbs/cleanup-checkpoint.json`.
+    pub fn save_checkpoint(&self, resume_after: &Path) -> Result<(), Error> {
+        self.save_checkpoint_at(&checkpoint_path()?, resume_after)
+    }
+
+    /// The position saved by `save_checkpoint` for this cache, if any.
+    pub fn checkpoint(&self) -> Result<Option<PathBuf>, Error> {
+        self.checkpoint_at(&checkpoint_path()?)
+    }
+
+    /// Remove the position saved by `save_checkpoint`, once the cleanup is
+    /// complete.
+    pub fn clear_checkpoint(&self) -> Result<(), Error> {
+        self.clear_checkpoint_at(&checkpoint_path()?)
+    }
+
+    fn save_checkpoint_at(&self, path: &Path, resume_after: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+
+        let checkpoint = Checkpoint {
+            cache_dir: self.cache_dir.clone(),
+            resume_after: resume_after.to_path_buf(),
+        };
+        let tmp = path.with_extension("json.tmp");
+        write(&tmp, serde_json::to_string(&checkpoint)?)
+            .and_then(|_| rename(&tmp, path))
+            .with_context(|| format!("Failed to write {}", path.to_string_lossy()))?;
+        debug!(
+            "Saved checkpoint after {}",
+            checkpoint.resume_after.to_string_lossy()
+        );
+
+        Ok(())
+    }
+
+    fn checkpoint_at(&self, path: &Path) -> Result<Option<PathBuf>, Error> {
+        let content = match read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(Error::Io {
+                    context: Some(format!("Failed to read {}", path.to_string_lossy())),
+                    source: e,
+                })
+            }
+        };
+        let checkpoint: Checkpoint = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid checkpoint {}", path.to_string_lossy()))?;
+        if checkpoint.cache_dir != self.cache_dir {
+            debug!(
+                "Ignoring checkpoint for another cache: {}",
+                checkpoint.cache_dir.to_string_lossy()
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(checkpoint.resume_after))
+    }
+
+    fn clear_checkpoint_at(&self, path: &Path) -> Result<(), Error> {
+        // keep the checkpoint of another cache
+        if self.checkpoint_at(path)?.is_none() {
+            return Ok(());
+        }
+
+        match remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Io {
+                context: Some(format!("Failed to remove {}", path.to_string_lossy())),
+                source: e,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs::create_dir_all;
+
+    #[test]
+    fn save_and_clear_checkpoint() {
+        let dir = TempDir::new("checkpoint");
+        let path = dir.path().join("state/cleanup-checkpoint.json");
+        let cache = dir.path().join("cache");
+        let other_cache = dir.path().join("other");
+        create_dir_all(&cache).unwrap();
+        create_dir_all(&other_cache).unwrap();
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        let other = UnThumbnailer::with_cache_dir(false, false, false, &other_cache).unwrap();
+        let last = cache.join("normal/a.png");
+
+        assert!(un.checkpoint_at(&path).unwrap().is_none());
+        un.save_checkpoint_at(&path, &last).unwrap();
+        assert_eq!(un.checkpoint_at(&path).unwrap(), Some(last.clone()));
+
+        // only for the same cache
+        assert!(other.checkpoint_at(&path).unwrap().is_none());
+        other.clear_checkpoint_at(&path).unwrap();
+        assert_eq!(un.checkpoint_at(&path).unwrap(), Some(last));
+
+        un.clear_checkpoint_at(&path).unwrap();
+        assert!(!path.exists());
+        un.clear_checkpoint_at(&path).unwrap();
+    }
+}
@@ -34,38 +34,212 @@ pub struct ProgramOptions {
     )]
     quiet: i8,
 
-    #[clap(short, long, help_heading = "FLAGS", global = true)]
+    #[clap(
+        short,
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_recursive"
+    )]
     /// Recurse through directories
     pub recursive: bool,
 
-    #[clap(short, long, help_heading = "FLAGS", global = true)]
+    #[clap(long, global = true, hide = true, overrides_with = "recursive")]
+    /// Disable --recursive, if enabled in the configuration file
+    pub no_recursive: bool,
+
+    #[clap(
+        short,
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_all"
+    )]
     /// Include hidden files and directories
     pub all: bool,
 
+    #[clap(long, global = true, hide = true, overrides_with = "all")]
+    /// Disable --all, if enabled in the configuration file
+    pub no_all: bool,
+
+    #[clap(
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_respect_ignore"
+    )]
+    /// Skip files ignored by .gitignore, .ignore or .thumbsignore files when recursing
+    pub respect_ignore: bool,
+
+    #[clap(long, global = true, hide = true, overrides_with = "respect_ignore")]
+    /// Disable --respect-ignore, if enabled in the configuration file
+    pub no_respect_ignore: bool,
+
+    #[clap(
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_quarantine"
+    )]
+    /// Move deleted thumbnails to a holding area, from which 'undo' can restore them
+    pub quarantine: bool,
+
+    #[clap(long, global = true, hide = true, overrides_with = "quarantine")]
+    /// Disable --quarantine, if enabled in the configuration file
+    pub no_quarantine: bool,
+
+    #[clap(
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_preview"
+    )]
+    /// Show the thumbnails in the terminal when reviewing them before deletion
+    pub preview: bool,
+
+    #[clap(long, global = true, hide = true, overrides_with = "preview")]
+    /// Disable --preview, if enabled in the configuration file
+    pub no_preview: bool,
+
+    #[clap(
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_dbus"
+    )]
+    /// Tell the thumbnail service on the D-Bus session bus about deleted, moved and
+    /// copied thumbnails
+    pub dbus: bool,
+
+    #[clap(long, global = true, hide = true, overrides_with = "dbus")]
+    /// Disable --dbus, if enabled in the configuration file
+    pub no_dbus: bool,
+
+    #[clap(
+        long,
+        help_heading = "FLAGS",
+        global = true,
+        overrides_with = "no_notify"
+    )]
+    /// Show the outcome of delete and cleanup, and any error, as a desktop
+    /// notification
+    pub notify: bool,
+
+    #[clap(long, global = true, hide = true, overrides_with = "notify")]
+    /// Disable --notify, if enabled in the configuration file
+    pub no_notify: bool,
+
+    #[clap(long, global = true, arg_enum, value_name = "FORMAT")]
+    /// Output format [default: human]
+    pub output: Option<OutputFormat>,
+
+    #[clap(long, global = true, arg_enum, value_name = "MODE")]
+    /// Progress reporting on stderr for cleanup, delete and stats [default: auto]
+    pub progress: Option<ProgressMode>,
+
+    #[clap(long, global = true, arg_enum, value_name = "PROTOCOL")]
+    /// Protocol used to show images in the terminal [default: detected]
+    pub image_protocol: Option<ImageProtocol>,
+
+    #[clap(
+        long,
+        global = true,
+        parse(from_os_str),
+        value_hint(ValueHint::FilePath),
+        value_name = "FILE"
+    )]
+    /// Configuration file to use instead of $XDG_CONFIG_HOME/thumbs/config.toml
+    pub config: Option<PathBuf>,
+
+    #[clap(
+        long,
+        global = true,
+        parse(from_os_str),
+        value_hint(ValueHint::DirPath),
+        value_name = "DIR"
+    )]
+    /// Thumbnail cache directory to use instead of $XDG_CACHE_HOME/thumbnails
+    pub cache_dir: Option<PathBuf>,
+
+    #[clap(long, global = true, value_name = "ID")]
+    /// Only use the thumbnail cache of the Flatpak or Snap application with this ID.
+    /// Can be used multiple times
+    pub app: Vec<String>,
+
     #[clap(subcommand)]
     pub cmd: Option<Command>,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human readable messages
+    Human,
+    /// JSON documents, for scripts
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressMode {
+    /// A progress bar, if stderr is a terminal
+    Auto,
+    /// JSON documents, one per line, for graphical frontends
+    Json,
+    /// No progress reporting
+    Never,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum)]
+pub enum ThumbnailSize {
+    /// 128x128 pixels
+    Normal,
+    /// 256x256 pixels
+    Large,
+    /// 512x512 pixels
+    XLarge,
+    /// 1024x1024 pixels
+    XxLarge,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageProtocol {
+    /// The kitty graphics protocol
+    Kitty,
+    /// Sixel graphics
+    Sixel,
+    /// iTerm2 inline images
+    Iterm2,
+    /// Colored half-block characters, for any terminal with true color
+    Blocks,
+}
+
 impl ProgramOptions {
     pub fn log_level_with_default(&self, default: i8) -> Option<LevelFilter> {
-        let level = default + self.verbose - self.quiet;
-        let new_level = match level {
-            i8::MIN..=0 => LevelFilter::Off,
-            1 => LevelFilter::Error,
-            2 => LevelFilter::Warn,
-            3 => LevelFilter::Info,
-            4 => LevelFilter::Debug,
-            5..=i8::MAX => LevelFilter::Trace,
-        };
+        let level = default
+            .saturating_add(self.verbose)
+            .saturating_sub(self.quiet);
 
         if level != default {
-            Some(new_level)
+            Some(level_filter(level))
         } else {
             None
         }
     }
 }
 
+pub fn level_filter(level: i8) -> LevelFilter {
+    match level {
+        i8::MIN..=0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5..=i8::MAX => LevelFilter::Trace,
+    }
+}
+
 #[derive(Debug, clap::Parser)]
 pub enum Command {
     /// Delete the thumbnails for the given files
@@ -97,11 +271,256 @@ pub enum Command {
         /// Actually delete thumbnails
         force: bool,
 
+        #[clap(long, help_heading = "FLAGS")]
+        /// Also rename thumbnails whose name doesn't match their URI (with -f/--force)
+        rename_mismatched: bool,
+
+        #[clap(long, help_heading = "FLAGS")]
+        /// Clean up the caches of all users from /etc/passwd (requires root)
+        all_users: bool,
+
+        #[clap(long, help_heading = "FLAGS", conflicts_with_all = &["all-users", "home"])]
+        /// Continue an interrupted cleanup from where it stopped
+        resume: bool,
+
+        #[clap(
+            long,
+            parse(from_os_str),
+            value_hint(ValueHint::DirPath),
+            value_name = "DIR",
+            conflicts_with = "all-users"
+        )]
+        /// Clean up the cache of the user with this home directory (requires root). Can
+        /// be used multiple times
+        home: Vec<PathBuf>,
+
         #[clap(short, long, value_name = "GLOB")]
         /// Include or exclude files and directories that match the given globs. Can be used
         /// multiple times. Globbing rules match .gitignore globs. Precede a glob with a !
         /// to exclude it.
         glob: Vec<String>,
+
+        #[clap(short, long, value_name = "NAME")]
+        /// Use the globs of the given profile from the configuration file
+        profile: Option<String>,
+    },
+    /// Export the thumbnails of files to a directory or archive
+    Export {
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::FilePath),
+            value_name = "FILE",
+            required = true
+        )]
+        /// Files whose thumbnails to export
+        files: Vec<PathBuf>,
+
+        #[clap(
+            long,
+            parse(from_os_str),
+            value_hint(ValueHint::DirPath),
+            value_name = "DIR",
+            required_unless_present = "tar",
+            conflicts_with = "tar"
+        )]
+        /// Directory to export the thumbnails to
+        to: Option<PathBuf>,
+
+        #[clap(
+            long,
+            parse(from_os_str),
+            value_hint(ValueHint::FilePath),
+            value_name = "ARCHIVE"
+        )]
+        /// Export the thumbnails to a single tar archive instead
+        tar: Option<PathBuf>,
+
+        #[clap(short, long, help_heading = "FLAGS")]
+        /// Remove the metadata of the exported thumbnails
+        strip: bool,
+
+        #[clap(short, long, help_heading = "FLAGS")]
+        /// Overwrite existing files
+        force: bool,
+    },
+    /// Move the thumbnails of the legacy cache in ~/.thumbnails to the cache
+    MigrateLegacy {
+        #[clap(short, long, help_heading = "FLAGS")]
+        /// Actually move thumbnails and remove the legacy cache
+        force: bool,
+    },
+    /// Restore the thumbnails deleted by the last operation run with --quarantine
+    Undo,
+    /// Show the changes made to the thumbnail cache
+    History {
+        /// Only show changes made since the given time.
+        ///
+        /// Can be either a RFC3339-like timestamp (`2020-01-01 11:10:00`) or a free-form
+        /// duration like `1year 15days 1week 2min` or `1h 6s 2ms`.
+        #[clap(short, long, parse(try_from_str = parse_last_accessed))]
+        since: Option<SystemTime>,
+
+        /// Only show changes made until the given time, in the same format as --since.
+        #[clap(short, long, parse(try_from_str = parse_last_accessed))]
+        until: Option<SystemTime>,
+
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::AnyPath),
+            value_name = "PATH"
+        )]
+        /// Only show changes to files or thumbnails under this path
+        path: Option<PathBuf>,
+    },
+    /// Show the thumbnail of a file in the terminal
+    Show {
+        #[clap(parse(from_os_str), value_hint(ValueHint::FilePath), value_name = "FILE")]
+        /// File whose thumbnail to show
+        file: PathBuf,
+
+        #[clap(short, long, value_name = "N")]
+        /// Maximum width of the image, in terminal columns [default: terminal width]
+        width: Option<u32>,
+    },
+    /// Ask the thumbnail service on the D-Bus session bus to generate thumbnails
+    Generate {
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::FilePath),
+            value_name = "FILE",
+            required = true
+        )]
+        /// Files to generate thumbnails for
+        files: Vec<PathBuf>,
+
+        #[clap(short, long, arg_enum, default_value = "normal", value_name = "SIZE")]
+        /// Size of the thumbnails
+        size: ThumbnailSize,
+    },
+    /// Serve the thumbnail cache service on the D-Bus session bus, for file managers
+    ServeDbus,
+    /// Show the number and size of the thumbnails in the cache
+    Stats,
+    /// Check the thumbnail cache for invalid or misplaced thumbnails
+    Fsck {
+        #[clap(long, help_heading = "FLAGS")]
+        /// Delete or fix the invalid entries
+        repair: bool,
+    },
+    /// Tile the thumbnails of the files in a directory into a single image
+    Montage {
+        #[clap(parse(from_os_str), value_hint(ValueHint::DirPath), value_name = "DIR")]
+        /// Directory whose files to include
+        dir: PathBuf,
+
+        #[clap(
+            short = 'o',
+            long = "to",
+            parse(from_os_str),
+            value_hint(ValueHint::FilePath),
+            value_name = "FILE"
+        )]
+        /// PNG image to create
+        image: PathBuf,
+
+        #[clap(short, long, value_name = "N", default_value = "5")]
+        /// Number of thumbnails per row
+        columns: u32,
+    },
+    /// Archive the thumbnail cache
+    Backup {
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::FilePath),
+            value_name = "ARCHIVE"
+        )]
+        /// Tar archive to create
+        archive: PathBuf,
+    },
+    /// Restore thumbnails from an archive created with 'backup'
+    Restore {
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::FilePath),
+            value_name = "ARCHIVE"
+        )]
+        /// Tar archive to restore thumbnails from
+        archive: PathBuf,
+    },
+    /// Move a file and carry its thumbnails along
+    Mv {
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::AnyPath),
+            value_name = "SOURCE"
+        )]
+        /// File or directory to move
+        source: PathBuf,
+
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::AnyPath),
+            value_name = "DEST"
+        )]
+        /// New location of the file, or directory to move it into
+        dest: PathBuf,
+    },
+    /// Copy the thumbnails of files to their copies
+    CpThumbs {
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::AnyPath),
+            value_name = "SOURCE"
+        )]
+        /// Original file or directory
+        source: PathBuf,
+
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::AnyPath),
+            value_name = "DEST"
+        )]
+        /// Copy of the file or directory
+        dest: PathBuf,
+    },
+    /// Carry thumbnails over to files that were moved from one directory to another
+    Relocate {
+        #[clap(short, long, help_heading = "FLAGS")]
+        /// Actually move thumbnails
+        force: bool,
+
+        #[clap(
+            long,
+            parse(from_os_str),
+            value_hint(ValueHint::DirPath),
+            value_name = "DIR"
+        )]
+        /// Directory the files were moved from
+        from: PathBuf,
+
+        #[clap(
+            long,
+            parse(from_os_str),
+            value_hint(ValueHint::DirPath),
+            value_name = "DIR"
+        )]
+        /// Directory the files were moved to
+        to: PathBuf,
+    },
+    /// Watch directories and delete the thumbnails of files as they change
+    Watch {
+        #[clap(short, long, help_heading = "FLAGS")]
+        /// Actually delete thumbnails
+        force: bool,
+
+        #[clap(
+            parse(from_os_str),
+            value_hint(ValueHint::DirPath),
+            value_name = "DIR",
+            required = true
+        )]
+        /// Directories to watch
+        dirs: Vec<PathBuf>,
     },
 }
 
@@ -0,0 +1,136 @@
+use crate::cli::{ImageProtocol, OutputFormat, ProgressMode};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Default options read from the thumbs configuration file.
+///
+/// Everything in here is optional; options given on the command line always
+/// take precedence (flags enabled here are disabled with `--no-FLAG`, and globs
+/// are added to the ones given on the command line).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Verbosity relative to the default (positive is more verbose).
+    pub verbose: i8,
+    pub recursive: bool,
+    pub all: bool,
+    pub respect_ignore: bool,
+    pub quarantine: bool,
+    pub preview: bool,
+    pub dbus: bool,
+    pub notify: bool,
+    /// Thumbnail cache directory to use instead of `$XDG_CACHE_HOME/thumbnails`.
+    pub cache_dir: Option<PathBuf>,
+    pub output: Option<OutputFormat>,
+    pub progress: Option<ProgressMode>,
+    pub image_protocol: Option<ImageProtocol>,
+    /// Globs always used by `cleanup`.
+    pub glob: Vec<String>,
+    /// Named cleanup profiles, selected with `cleanup --profile NAME`.
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    /// Globs added to the default ones when this profile is used.
+    ///
+    /// Profiles can't enable `force`: deleting always takes `-f/--force` on the
+    /// command line.
+    pub glob: Vec<String>,
+}
+
+impl Config {
+    /// Load the configuration file at `path`, or at
+    /// `$XDG_CONFIG_HOME/thumbs/config.toml` if no path is given.
+    ///
+    /// A missing default configuration file is not an error and results in
+    /// the default configuration.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => match default_config_path() {
+                Some(p) if p.exists() => p,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.to_string_lossy()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid config file {}", path.to_string_lossy()))
+    }
+
+    /// Find the cleanup profile named `name`.
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown cleanup profile '{}'", name))
+    }
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|mut p| {
+        p.push("thumbs/config.toml");
+        p
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs::write;
+
+    fn load(dir: &TempDir, content: &str) -> Result<Config> {
+        let path = dir.path().join("config.toml");
+        write(&path, content).unwrap();
+        Config::load(Some(&path))
+    }
+
+    #[test]
+    fn load_options_and_profiles() {
+        let dir = TempDir::new("config-load");
+        let config = load(
+            &dir,
+            r#"
+            recursive = true
+            verbose = -1
+            output = "json"
+            cache-dir = "/tmp/thumbnails"
+            glob = ["!/run/media/*"]
+
+            [profiles.nightly]
+            glob = ["!*.mkv"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.recursive);
+        assert!(!config.quarantine);
+        assert_eq!(config.verbose, -1);
+        assert_eq!(config.output, Some(OutputFormat::Json));
+        assert_eq!(config.cache_dir, Some(PathBuf::from("/tmp/thumbnails")));
+        assert_eq!(config.glob, vec!["!/run/media/*"]);
+        assert_eq!(config.profile("nightly").unwrap().glob, vec!["!*.mkv"]);
+        assert!(config.profile("weekly").is_err());
+    }
+
+    #[test]
+    fn reject_unknown_options() {
+        let dir = TempDir::new("config-unknown");
+
+        assert!(load(&dir, "recursiv = true").is_err());
+        // profiles only have globs
+        assert!(load(&dir, "[profiles.nightly]\nforce = true").is_err());
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = TempDir::new("config-missing");
+
+        assert!(Config::load(Some(&dir.path().join("config.toml"))).is_err());
+    }
+}
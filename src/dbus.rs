@@ -0,0 +1,651 @@
+//! Talking to the thumbnail services of the desktop (like Tumbler) on the D-Bus
+//! session bus, following the Thumbnail Management D-Bus specification.
+//!
+//! `org.freedesktop.thumbnails.Thumbnailer1` generates thumbnails, and
+//! `org.freedesktop.thumbnails.Cache1` is told about files that were deleted,
+//! moved or copied, so that it can update the cache and running applications
+//! drop their stale previews. `UnThumbnailer::serve_dbus` provides the latter
+//! on desktops without such a service.
+//!
+//! `notify` shows desktop notifications through `org.freedesktop.Notifications`.
+
+use crate::{
+    delete_thumbnail, file_url, is_missing, Error, FailedFile, FailedThumbnail, GenerateResults,
+    Thumbnail, UnThumbnailer,
+};
+use anyhow::{Context, Result};
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use url::Url;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::Type;
+use zbus::zvariant::Value;
+use zbus::{fdo, interface, MatchRule};
+
+const THUMBNAILER_NAME: &str = "org.freedesktop.thumbnails.Thumbnailer1";
+const THUMBNAILER_PATH: &str = "/org/freedesktop/thumbnails/Thumbnailer1";
+const CACHE_NAME: &str = "org.freedesktop.thumbnails.Cache1";
+const CACHE_PATH: &str = "/org/freedesktop/thumbnails/Cache1";
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+/// A connection to the thumbnail services on the session bus.
+///
+/// The services are started by the bus when they are first called, so
+/// connecting succeeds even if none is installed: calls fail instead.
+#[derive(Debug)]
+pub struct ThumbnailService {
+    connection: Connection,
+}
+
+impl ThumbnailService {
+    /// Connect to the session bus of the user.
+    pub fn connect() -> Result<Self, Error> {
+        let connection =
+            Connection::session().context("Failed to connect to the D-Bus session bus")?;
+
+        Ok(ThumbnailService { connection })
+    }
+
+    /// Tell the cache service that the files at `files` were deleted.
+    pub fn delete(&self, files: &[PathBuf]) -> Result<(), Error> {
+        self.call_cache("Delete", &(uris(files)?,))
+    }
+
+    /// Tell the cache service that files were moved, given as pairs of their
+    /// old and new locations.
+    pub fn move_files(&self, moved: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+        let (from, to) = uri_pairs(moved)?;
+        self.call_cache("Move", &(from, to))
+    }
+
+    /// Tell the cache service that files were copied, given as pairs of the
+    /// originals and their copies.
+    pub fn copy_files(&self, copied: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+        let (from, to) = uri_pairs(copied)?;
+        self.call_cache("Copy", &(from, to))
+    }
+
+    /// Ask the cache service to remove the thumbnails of files under the
+    /// directories `dirs` that no longer exist. `since` is a Unix timestamp that
+    /// limits the cleanup to older thumbnails, or 0 for all of them.
+    pub fn cleanup(&self, dirs: &[PathBuf], since: u32) -> Result<(), Error> {
+        self.call_cache("Cleanup", &(uris(dirs)?, since))
+    }
+
+    /// Ask the thumbnailer service to generate thumbnails of size `flavor`
+    /// (`normal`, `large`, `x-large` or `xx-large`) for the files at `files`,
+    /// and wait until it is done.
+    pub fn generate(&self, files: &[PathBuf], flavor: &str) -> Result<GenerateResults, Error> {
+        let mime = MimeDatabase::load();
+        let mut uris = Vec::new();
+        let mut mime_types = Vec::new();
+        for file in files {
+            let uri = file_url(file)
+                .with_context(|| format!("Failed to read {}", file.to_string_lossy()))?;
+            uris.push(uri.to_string());
+            mime_types.push(mime.mime_type(file));
+        }
+
+        // subscribe before queueing, to not miss anything
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(THUMBNAILER_NAME)?
+            .path(THUMBNAILER_PATH)?
+            .build();
+        let signals = MessageIterator::for_match_rule(rule, &self.connection, None)?;
+
+        let reply = self
+            .connection
+            .call_method(
+                Some(THUMBNAILER_NAME),
+                THUMBNAILER_PATH,
+                Some(THUMBNAILER_NAME),
+                "Queue",
+                &(&uris, &mime_types, flavor, "default", 0u32),
+            )
+            .context("Failed to call the thumbnailer service")?;
+        let handle: u32 = reply.body().deserialize()?;
+        debug!("Queued {} file(s) with handle {}", uris.len(), handle);
+
+        let mut results = GenerateResults::default();
+        for signal in signals {
+            let signal = signal?;
+            let header = signal.header();
+            let member = match header.member() {
+                Some(m) => m.as_str(),
+                None => continue,
+            };
+            match member {
+                "Ready" => {
+                    let (h, ready): (u32, Vec<String>) = signal.body().deserialize()?;
+                    if h == handle {
+                        results
+                            .generated
+                            .extend(ready.iter().filter_map(|u| uri_path(u)));
+                    }
+                }
+                "Error" => {
+                    let (h, failed, code, message): (u32, Vec<String>, i32, String) =
+                        signal.body().deserialize()?;
+                    if h == handle {
+                        debug!("Thumbnailer error {}: {}", code, message);
+                        results
+                            .failed
+                            .extend(failed.iter().filter_map(|u| uri_path(u)).map(|file| {
+                                FailedFile {
+                                    file,
+                                    error: message.clone(),
+                                }
+                            }));
+                    }
+                }
+                "Finished" => {
+                    let h: u32 = signal.body().deserialize()?;
+                    if h == handle {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn call_cache<B>(&self, method: &str, body: &B) -> Result<(), Error>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        self.connection
+            .call_method(Some(CACHE_NAME), CACHE_PATH, Some(CACHE_NAME), method, body)
+            .with_context(|| format!("Failed to call {} on the cache service", method))?;
+        debug!("Called {} on the cache service", method);
+
+        Ok(())
+    }
+}
+
+/// Show a desktop notification with `summary` and `body`, through the
+/// notification server on the session bus. `critical` notifications stay until
+/// they are dismissed.
+pub fn notify(summary: &str, body: &str, critical: bool) -> Result<(), Error> {
+    let connection = Connection::session().context("Failed to connect to the D-Bus session bus")?;
+    let (icon, urgency) = if critical {
+        ("dialog-error", 2u8)
+    } else {
+        ("dialog-information", 1u8)
+    };
+    let mut hints = HashMap::new();
+    hints.insert("urgency", Value::from(urgency));
+    // servers may render the body as markup, and file names aren't
+    let body = body
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+
+    connection
+        .call_method(
+            Some(NOTIFICATIONS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_NAME),
+            "Notify",
+            &(
+                "thumbs",
+                0u32,
+                icon,
+                summary,
+                body,
+                Vec::<&str>::new(),
+                hints,
+                -1i32,
+            ),
+        )
+        .context("Failed to call the notification server")?;
+    debug!("Sent a desktop notification: {}", summary);
+
+    Ok(())
+}
+
+impl UnThumbnailer {
+    /// Serve `org.freedesktop.thumbnails.Cache1` on the session bus, to apply
+    /// the changes other applications tell the cache service about: deleting,
+    /// moving and copying the thumbnails of files, and cleaning up the
+    /// thumbnails of deleted directories.
+    ///
+    /// Only local files are supported. This only returns if serving fails.
+    pub fn serve_dbus(mut self) -> Result<(), Error> {
+        // the cache may only be created while serving
+        if self.apps.as_ref().is_none_or(|a| a.is_empty()) {
+            for flavor in ["normal", "large", "x-large", "xx-large"] {
+                let dir = self.cache_dir.join(flavor);
+                if !self.cache_locs.contains(&dir) {
+                    self.cache_locs.push(dir);
+                }
+            }
+        }
+
+        // don't take over from another service, or let it take over silently
+        let _connection = Builder::session()?
+            .allow_name_replacements(false)
+            .replace_existing_names(false)
+            .name(CACHE_NAME)?
+            .serve_at(CACHE_PATH, CacheInterface { un: self })?
+            .build()
+            .with_context(|| {
+                format!(
+                    "Failed to serve {}, is another thumbnail service running?",
+                    CACHE_NAME
+                )
+            })?;
+        info!("Serving {} on the session bus", CACHE_NAME);
+
+        // requests are handled by the threads of the connection
+        loop {
+            std::thread::park();
+        }
+    }
+}
+
+/// `org.freedesktop.thumbnails.Cache1`, implemented with an `UnThumbnailer`.
+struct CacheInterface {
+    un: UnThumbnailer,
+}
+
+#[interface(name = "org.freedesktop.thumbnails.Cache1")]
+impl CacheInterface {
+    /// Delete the thumbnails of the files at `uris`.
+    fn delete(&self, uris: Vec<String>) {
+        debug!("Delete {:?}", uris);
+        let action = self.un.delete_action();
+        let mut deleted = Vec::new();
+        let mut failed = Vec::new();
+        for file in uris.iter().filter_map(|u| uri_path(u)) {
+            match self.un.thumbnails_of(&file) {
+                Ok(found) => {
+                    for entry in found {
+                        let th = Thumbnail {
+                            thumbnail: entry.thumbnail().to_path_buf(),
+                            file: file.clone(),
+                            app: entry.app().map(String::from),
+                        };
+                        delete_thumbnail(th, action, &mut deleted, &mut failed, &self.un);
+                    }
+                }
+                Err(e) => warn!("Failed for {}: {:#}", file.to_string_lossy(), e),
+            }
+        }
+        report_deleted(&deleted, &failed);
+    }
+
+    /// Carry the thumbnails of the files (or directories) at `from_uris` over to
+    /// their new locations at `to_uris`.
+    #[zbus(name = "Move")]
+    fn move_files(&self, from_uris: Vec<String>, to_uris: Vec<String>) -> fdo::Result<()> {
+        debug!("Move {:?} to {:?}", from_uris, to_uris);
+        for (from, to) in path_pairs(&from_uris, &to_uris)? {
+            let moved = if to.is_dir() {
+                self.un.relocate(&from, &to, false)
+            } else {
+                self.un.move_thumbnails(&from, &to, false)
+            };
+            match moved {
+                Ok(m) if !m.is_empty() => info!(
+                    "Moved {} thumbnail(s) for '{}'",
+                    m.len(),
+                    from.to_string_lossy()
+                ),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Failed to move the thumbnails of {}: {:#}",
+                    from.to_string_lossy(),
+                    e
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the thumbnails of the files (or directories) at `from_uris` to their
+    /// copies at `to_uris`.
+    #[zbus(name = "Copy")]
+    fn copy_files(&self, from_uris: Vec<String>, to_uris: Vec<String>) -> fdo::Result<()> {
+        debug!("Copy {:?} to {:?}", from_uris, to_uris);
+        for (from, to) in path_pairs(&from_uris, &to_uris)? {
+            match self.un.copy_thumbnails(&from, &to) {
+                Ok(c) if !c.is_empty() => info!(
+                    "Copied {} thumbnail(s) for '{}'",
+                    c.len(),
+                    from.to_string_lossy()
+                ),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Failed to copy the thumbnails of {}: {:#}",
+                    from.to_string_lossy(),
+                    e
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete the thumbnails of files under `base_uris` (or anywhere if empty)
+    /// that no longer exist, and whose `Thumb::MTime` is at most `since` (unless
+    /// it is 0).
+    fn cleanup(&self, base_uris: Vec<String>, since: u32) {
+        debug!("Cleanup {:?} since {}", base_uris, since);
+        let bases: Vec<_> = base_uris.iter().filter_map(|u| uri_path(u)).collect();
+        if bases.is_empty() && !base_uris.is_empty() {
+            return;
+        }
+
+        let action = self.un.delete_action();
+        let mut deleted = Vec::new();
+        let mut failed = Vec::new();
+        for entry in self.un.scan().filter_map(|e| e.ok()) {
+            let file = match entry.file() {
+                Ok(Some(f)) => f,
+                _ => continue,
+            };
+            if !bases.is_empty() && !bases.iter().any(|b| file.starts_with(b)) {
+                continue;
+            }
+            if since != 0
+                && entry
+                    .mtime()
+                    .ok()
+                    .flatten()
+                    .is_none_or(|m| m > since.into())
+            {
+                continue;
+            }
+            if is_missing(&file) {
+                let th = Thumbnail {
+                    thumbnail: entry.thumbnail().to_path_buf(),
+                    file,
+                    app: entry.app().map(String::from),
+                };
+                delete_thumbnail(th, action, &mut deleted, &mut failed, &self.un);
+            }
+        }
+        report_deleted(&deleted, &failed);
+    }
+}
+
+fn report_deleted(deleted: &[Thumbnail], failed: &[FailedThumbnail]) {
+    if !deleted.is_empty() {
+        info!("Deleted {} thumbnail(s)", deleted.len());
+    }
+    for f in failed {
+        warn!(
+            "Failed to delete {}: {}",
+            f.thumbnail.thumbnail.to_string_lossy(),
+            f.reason
+        );
+    }
+}
+
+/// The pairs of local paths of `from_uris` and `to_uris`.
+fn path_pairs(from_uris: &[String], to_uris: &[String]) -> fdo::Result<Vec<(PathBuf, PathBuf)>> {
+    if from_uris.len() != to_uris.len() {
+        return Err(fdo::Error::InvalidArgs(
+            "Both URI lists must have the same length".to_string(),
+        ));
+    }
+
+    Ok(from_uris
+        .iter()
+        .zip(to_uris)
+        .filter_map(|(f, t)| Some((uri_path(f)?, uri_path(t)?)))
+        .collect())
+}
+
+/// The `file://` URIs of `paths`, without duplicates.
+fn uris(paths: &[PathBuf]) -> Result<Vec<String>> {
+    let mut seen = HashSet::new();
+    paths
+        .iter()
+        .filter(|p| seen.insert(*p))
+        .map(|p| file_url(p).map(String::from))
+        .collect()
+}
+
+/// The `file://` URIs of the pairs of paths in `pairs`, without duplicates.
+fn uri_pairs(pairs: &[(PathBuf, PathBuf)]) -> Result<(Vec<String>, Vec<String>)> {
+    let mut seen = HashSet::new();
+    let mut from = Vec::new();
+    let mut to = Vec::new();
+    for (f, t) in pairs {
+        if seen.insert((f, t)) {
+            from.push(file_url(f)?.to_string());
+            to.push(file_url(t)?.to_string());
+        }
+    }
+
+    Ok((from, to))
+}
+
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    Url::parse(uri).ok()?.to_file_path().ok()
+}
+
+/// The file name globs of the shared MIME-info database, used to tell the
+/// thumbnailer service the MIME type of files.
+struct MimeDatabase {
+    globs: Vec<MimeGlob>,
+}
+
+struct MimeGlob {
+    weight: u32,
+    mime_type: String,
+    /// A file name, or a `*.ext` pattern.
+    pattern: String,
+    case_sensitive: bool,
+}
+
+impl MimeDatabase {
+    /// Read the `mime/globs2` files of `$XDG_DATA_HOME` and `$XDG_DATA_DIRS`.
+    fn load() -> Self {
+        let data_dirs = std::env::var("XDG_DATA_DIRS")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+        let dirs = dirs::data_dir()
+            .into_iter()
+            .chain(std::env::split_paths(&data_dirs));
+
+        let mut mime = MimeDatabase { globs: Vec::new() };
+        for dir in dirs {
+            let path = dir.join("mime/globs2");
+            let content = match read_to_string(&path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            trace!("Reading MIME globs from {}", path.to_string_lossy());
+            mime.add_globs(&content);
+        }
+
+        mime
+    }
+
+    /// Add the globs of the `globs2` file in `content`. Only literal names and
+    /// extensions are supported.
+    fn add_globs(&mut self, content: &str) {
+        for line in content.lines().filter(|l| !l.starts_with('#')) {
+            let mut fields = line.split(':');
+            let (weight, mime_type, pattern) = match (
+                fields.next().and_then(|w| w.parse().ok()),
+                fields.next(),
+                fields.next(),
+            ) {
+                (Some(w), Some(m), Some(p)) => (w, m, p),
+                _ => continue,
+            };
+            let literal = pattern.strip_prefix("*.").unwrap_or(pattern);
+            if literal.contains(['*', '?', '[']) {
+                continue;
+            }
+            let case_sensitive = fields.next().is_some_and(|f| f.contains("cs"));
+            self.globs.push(MimeGlob {
+                weight,
+                mime_type: mime_type.to_string(),
+                pattern: if case_sensitive {
+                    pattern.to_string()
+                } else {
+                    pattern.to_lowercase()
+                },
+                case_sensitive,
+            });
+        }
+    }
+
+    /// The MIME type of the file at `path`, guessed from its name.
+    fn mime_type(&self, path: &Path) -> String {
+        if path.is_dir() {
+            return "inode/directory".to_string();
+        }
+        let name = match path.file_name() {
+            Some(n) => n.to_string_lossy(),
+            None => return "application/octet-stream".to_string(),
+        };
+        let lower = name.to_lowercase();
+
+        self.globs
+            .iter()
+            .filter(|g| {
+                let name = if g.case_sensitive { &*name } else { &*lower };
+                match g.pattern.strip_prefix('*') {
+                    Some(ext) => name.ends_with(ext),
+                    None => name == g.pattern,
+                }
+            })
+            // longer patterns are more specific, and case-sensitive ones too
+            .max_by_key(|g| (g.weight, g.pattern.len(), g.case_sensitive))
+            .map_or_else(
+                || "application/octet-stream".to_string(),
+                |g| g.mime_type.clone(),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{thumbnailed_file, TempDir};
+    use crate::thumbnail_file_name;
+    use std::fs::rename;
+    use std::os::unix::net::UnixStream;
+    use zbus::Guid;
+
+    /// A client talking to `Cache1` served for the cache `cache`, over a
+    /// peer-to-peer connection. The server connection must be kept alive.
+    fn serve_cache(cache: &Path) -> (ThumbnailService, Connection) {
+        let un = UnThumbnailer::with_cache_dir(false, false, false, cache).unwrap();
+        let (server, client) = UnixStream::pair().unwrap();
+        // both ends of the handshake block
+        let server = std::thread::spawn(move || {
+            Builder::async_io_unix_stream(server)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at(CACHE_PATH, CacheInterface { un })
+                .unwrap()
+                .build()
+                .unwrap()
+        });
+        let client = Builder::async_io_unix_stream(client).p2p().build().unwrap();
+
+        (
+            ThumbnailService { connection: client },
+            server.join().unwrap(),
+        )
+    }
+
+    #[test]
+    fn delete_thumbnails() {
+        let dir = TempDir::new("dbus-delete");
+        let cache = dir.path().join("cache");
+        let deleted = dir.path().join("deleted");
+        let kept = dir.path().join("kept");
+        let deleted_thumb = thumbnailed_file(&cache, &deleted);
+        let kept_thumb = thumbnailed_file(&cache, &kept);
+
+        let (service, _server) = serve_cache(&cache);
+        service.delete(&[deleted]).unwrap();
+
+        assert!(!deleted_thumb.exists());
+        assert!(kept_thumb.exists());
+    }
+
+    #[test]
+    fn move_and_copy_thumbnails() {
+        let dir = TempDir::new("dbus-move");
+        let cache = dir.path().join("cache");
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        let copy = dir.path().join("copy");
+        let from_thumb = thumbnailed_file(&cache, &from);
+
+        let (service, _server) = serve_cache(&cache);
+        rename(&from, &to).unwrap();
+        service.move_files(&[(from, to.clone())]).unwrap();
+
+        let to_thumb = cache
+            .join("normal")
+            .join(thumbnail_file_name(&file_url(&to).unwrap()));
+        assert!(!from_thumb.exists());
+        assert!(to_thumb.exists());
+
+        std::fs::copy(&to, &copy).unwrap();
+        service.copy_files(&[(to, copy.clone())]).unwrap();
+
+        let copy_thumb = cache
+            .join("normal")
+            .join(thumbnail_file_name(&file_url(&copy).unwrap()));
+        assert!(to_thumb.exists());
+        assert!(copy_thumb.exists());
+    }
+
+    #[test]
+    fn mime_types_from_globs() {
+        let mut mime = MimeDatabase { globs: Vec::new() };
+        mime.add_globs(
+            "# comment\n\
+             50:image/png:*.png\n\
+             50:text/x-c++src:*.C:cs\n\
+             50:text/x-csrc:*.c\n\
+             60:text/x-makefile:Makefile\n\
+             10:text/x-makefile:*.mk\n\
+             50:application/x-compressed-tar:*.tar.gz\n\
+             40:application/gzip:*.gz\n\
+             50:text/x-readme:README*\n\
+             invalid line\n",
+        );
+
+        let mime_type = |name: &str| mime.mime_type(&Path::new("/nonexistent").join(name));
+        assert_eq!(mime_type("a.PNG"), "image/png");
+        assert_eq!(mime_type("a.C"), "text/x-c++src");
+        assert_eq!(mime_type("a.c"), "text/x-csrc");
+        assert_eq!(mime_type("Makefile"), "text/x-makefile");
+        assert_eq!(mime_type("a.tar.gz"), "application/x-compressed-tar");
+        assert_eq!(mime_type("a.gz"), "application/gzip");
+        assert_eq!(mime_type("README.md"), "application/octet-stream");
+        assert_eq!(mime_type("a"), "application/octet-stream");
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let dir = TempDir::new("dbus-invalid");
+        let (service, _server) = serve_cache(&dir.path().join("cache"));
+
+        let err = service
+            .call_cache("Move", &(vec!["file:///a"], Vec::<&str>::new()))
+            .unwrap_err();
+        assert!(format!("{:#}", anyhow::Error::from(err)).contains("same length"));
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// The errors returned by the thumbs library.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The cache directory of the user (`$XDG_CACHE_HOME`) couldn't be found.
+    NoCacheDirectory,
+    /// Another directory of the user couldn't be found, like their home or data
+    /// directory.
+    NoDirectory(&'static str),
+    /// A path couldn't be turned into a `file://` URI.
+    NonAbsolutePath(PathBuf),
+    /// A thumbnail isn't a valid PNG image.
+    PngDecode(String),
+    /// An I/O error, with a description of what failed if known.
+    Io {
+        context: Option<String>,
+        source: io::Error,
+    },
+    /// Any other error.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoCacheDirectory => write!(f, "Could not find cache directory"),
+            Error::NoDirectory(name) => write!(f, "Could not find {} directory", name),
+            Error::NonAbsolutePath(path) => write!(f, "Non absolute path: {:?}", path),
+            Error::PngDecode(e) => write!(f, "{}", e),
+            Error::Io {
+                context: Some(context),
+                ..
+            } => write!(f, "{}", context),
+            Error::Io { source, .. } => write!(f, "{}", source),
+            Error::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io {
+                context: Some(_),
+                source,
+            } => Some(source),
+            Error::Io { source, .. } => source.source(),
+            Error::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io {
+            context: None,
+            source,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        // typed errors raised deeper, maybe with some added context
+        if error.is::<Error>() {
+            return error.downcast().unwrap();
+        }
+
+        if error.is::<io::Error>() {
+            let has_context = !error.chain().next().is_some_and(|e| e.is::<io::Error>());
+            let context = if has_context {
+                Some(error.to_string())
+            } else {
+                None
+            };
+            match error.downcast() {
+                Ok(source) => return Error::Io { context, source },
+                Err(error) => return Error::Other(error),
+            }
+        }
+
+        Error::Other(error)
+    }
+}
+
+/// Errors converted to `Error` through `anyhow::Error`, to keep their context.
+macro_rules! from_other {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Error {
+                fn from(error: $t) -> Self {
+                    anyhow::Error::from(error).into()
+                }
+            }
+        )*
+    };
+}
+
+from_other!(
+    walkdir::Error,
+    serde_json::Error,
+    std::path::StripPrefixError,
+    std::time::SystemTimeError,
+    url::ParseError
+);
+
+#[cfg(feature = "dbus")]
+from_other!(zbus::Error);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    fn not_found() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "not found")
+    }
+
+    #[test]
+    fn typed_errors_are_kept() {
+        let error: Error = anyhow::Error::from(Error::NoCacheDirectory).into();
+        assert!(matches!(error, Error::NoCacheDirectory));
+
+        // the context is lost, but not the kind of error
+        let error: Error = Err::<(), _>(Error::PngDecode("bad".to_string()))
+            .context("Failed to read a.png")
+            .unwrap_err()
+            .into();
+        assert!(matches!(error, Error::PngDecode(e) if e == "bad"));
+    }
+
+    #[test]
+    fn io_errors() {
+        let error: Error = anyhow::Error::from(not_found()).into();
+        assert!(matches!(
+            &error,
+            Error::Io { context: None, source } if source.kind() == io::ErrorKind::NotFound
+        ));
+        assert_eq!(error.to_string(), "not found");
+
+        let error: Error = Err::<(), _>(not_found())
+            .context("Failed to open a.png")
+            .unwrap_err()
+            .into();
+        assert!(matches!(
+            &error,
+            Error::Io { context: Some(c), source }
+                if c == "Failed to open a.png" && source.kind() == io::ErrorKind::NotFound
+        ));
+        assert_eq!(error.to_string(), "Failed to open a.png");
+        assert_eq!(
+            std::error::Error::source(&error).map(|e| e.to_string()),
+            Some("not found".to_string())
+        );
+    }
+
+    #[test]
+    fn other_errors() {
+        let error: Error = anyhow!("something else").into();
+        assert!(matches!(&error, Error::Other(_)));
+        assert_eq!(error.to_string(), "something else");
+
+        let error: Error = url::Url::parse("not a url").unwrap_err().into();
+        assert!(matches!(error, Error::Other(_)));
+    }
+}
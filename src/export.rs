@@ -0,0 +1,351 @@
+use crate::{png, Error, Thumbnail, UnThumbnailer};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, read, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where to export thumbnails to.
+#[derive(Debug, Clone, Copy)]
+pub enum ExportTarget<'a> {
+    /// A directory, created if needed.
+    Dir(&'a Path),
+    /// A tar archive.
+    Tar(&'a Path),
+}
+
+/// A thumbnail exported under `name`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedThumbnail {
+    pub thumbnail: Thumbnail,
+    pub name: String,
+}
+
+impl UnThumbnailer {
+    /// Find the largest thumbnail for the file at `path`, ignoring the
+    /// placeholders for failed thumbnails. Thumbnails that can't be read are
+    /// skipped.
+    pub fn best_thumbnail(&self, path: &Path) -> Result<Option<Thumbnail>, Error> {
+        Ok(self.best_thumbnail_data(path)?.map(|(thumb, _)| thumb))
+    }
+
+    /// Same as `best_thumbnail`, but also returns the data of the thumbnail.
+    fn best_thumbnail_data(&self, path: &Path) -> Result<Option<(Thumbnail, Vec<u8>)>> {
+        let mut best = None;
+        let mut best_size = 0;
+        for thumb in self.locate(path)? {
+            if is_failed_thumbnail(&thumb.thumbnail) {
+                continue;
+            }
+            // another size may still be fine
+            let (data, width, height) = match read_sized(&thumb.thumbnail) {
+                Ok(d) => d,
+                Err(e) => {
+                    debug!("{} for {}", e, thumb.thumbnail.to_string_lossy());
+                    continue;
+                }
+            };
+            if width.max(height) > best_size {
+                best_size = width.max(height);
+                best = Some((thumb, data));
+            }
+        }
+
+        Ok(best)
+    }
+
+    /// The best thumbnail of the file at `path` and its data, stripped of its
+    /// metadata if `strip` is set.
+    fn export_data(&self, path: &Path, strip: bool) -> Result<Option<(Thumbnail, Vec<u8>)>> {
+        let (thumb, mut data) = match self.best_thumbnail_data(path)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if strip {
+            data = png::rewrite_text(&data, &[], false)?;
+        }
+
+        Ok(Some((thumb, data)))
+    }
+
+    /// Export the best thumbnail of each file in `files` to `target`, named after
+    /// the file (e.g. `MyMovie.mkv.png`). If `strip` is set, the metadata of the
+    /// thumbnails is removed.
+    ///
+    /// Existing files (the archive, or files in the directory) are only
+    /// overwritten if `overwrite` is set. Files whose thumbnail can't be read or
+    /// would overwrite a file are skipped with a warning. The archive is always
+    /// completed, with the thumbnails exported until then.
+    pub fn export(
+        &self,
+        files: &[PathBuf],
+        target: ExportTarget,
+        strip: bool,
+        overwrite: bool,
+    ) -> Result<Vec<ExportedThumbnail>, Error> {
+        let mut sink = match target {
+            ExportTarget::Dir(dir) => {
+                create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.to_string_lossy()))?;
+                Sink::Dir(dir)
+            }
+            ExportTarget::Tar(path) => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .create_new(!overwrite)
+                    .open(path)
+                    .map_err(|e| {
+                        if e.kind() == ErrorKind::AlreadyExists {
+                            anyhow!(
+                                "Not overwriting {}, which already exists",
+                                path.to_string_lossy()
+                            )
+                        } else {
+                            anyhow::Error::new(e)
+                                .context(format!("Failed to create {}", path.to_string_lossy()))
+                        }
+                    })?;
+                Sink::Tar(tar::Builder::new(file))
+            }
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut names = HashSet::new();
+        let mut exported = Vec::new();
+        let mut written = Ok(());
+        for file in files {
+            // a file that can't be exported doesn't stop the others
+            let (thumb, data) = match self.export_data(file, strip) {
+                Ok(Some(found)) => found,
+                Ok(None) => {
+                    warn!("Found no thumbnail for {}", file.to_string_lossy());
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to export the thumbnail for {}: {:#}",
+                        file.to_string_lossy(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            let name = match unique_name(file, &mut names) {
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("{:#}", e);
+                    continue;
+                }
+            };
+            if let Sink::Dir(dir) = sink {
+                let dest = dir.join(&name);
+                if !overwrite && dest.exists() {
+                    warn!(
+                        "Not overwriting {}, which already exists",
+                        dest.to_string_lossy()
+                    );
+                    continue;
+                }
+            }
+
+            info!(
+                "Exporting a thumbnail for '{}' as '{}'",
+                file.to_string_lossy(),
+                name
+            );
+            written = sink.write(&name, &data, now);
+            if written.is_err() {
+                break;
+            }
+
+            exported.push(ExportedThumbnail {
+                thumbnail: thumb,
+                name,
+            });
+        }
+
+        // an archive is only valid once finished, even if incomplete
+        let finished = sink.finish();
+        written?;
+        finished?;
+
+        Ok(exported)
+    }
+}
+
+enum Sink<'a> {
+    Dir(&'a Path),
+    Tar(tar::Builder<File>),
+}
+
+impl Sink<'_> {
+    fn write(&mut self, name: &str, data: &[u8], mtime: u64) -> Result<()> {
+        match self {
+            Sink::Dir(dir) => {
+                let dest = dir.join(name);
+                std::fs::write(&dest, data)
+                    .with_context(|| format!("Failed to write {}", dest.to_string_lossy()))?;
+            }
+            Sink::Tar(archive) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(mtime);
+                archive.append_data(&mut header, name, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Sink::Tar(archive) = self {
+            archive.into_inner()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the thumbnail at `path`, along with its width and height.
+fn read_sized(path: &Path) -> Result<(Vec<u8>, u32, u32)> {
+    let data = read(path)?;
+    let (width, height) = png::dimensions(&data)?;
+
+    Ok((data, width, height))
+}
+
+fn is_failed_thumbnail(thumb: &Path) -> bool {
+    thumb
+        .parent()
+        .and_then(|p| p.parent())
+        .is_some_and(|p| p.ends_with("fail"))
+}
+
+/// Name the thumbnail of `file` after it, adding a number before the extension of
+/// the file if another one already has the same name (e.g. `MyMovie-2.mkv.png`).
+fn unique_name(file: &Path, names: &mut HashSet<String>) -> Result<String> {
+    let stem = file
+        .file_stem()
+        .ok_or_else(|| anyhow!("Invalid file name: {}", file.to_string_lossy()))?
+        .to_string_lossy();
+    let extension = file
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut name = format!("{}{}.png", stem, extension);
+    let mut i = 1;
+    while names.contains(&name) {
+        i += 1;
+        name = format!("{}-{}{}.png", stem, i, extension);
+    }
+    names.insert(name.clone());
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::Image;
+    use crate::testutil::{thumbnailed_file, TempDir};
+    use std::fs::{create_dir_all, write};
+
+    /// A `size` x `size` thumbnail in the directory `dir` of the cache, for the
+    /// same file as the thumbnail `normal`.
+    fn sized_thumbnail(normal: &Path, dir: &str, size: u32) -> PathBuf {
+        let path = normal
+            .parent()
+            .unwrap()
+            .join(dir)
+            .join(normal.file_name().unwrap());
+        create_dir_all(path.parent().unwrap()).unwrap();
+        let image = Image::with_color(size, size, [0, 0, 0, 255]);
+        write(&path, image.encode().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn best_thumbnail_skips_unreadable_sizes() {
+        let dir = TempDir::new("export-best");
+        let cache = dir.path().join("cache");
+        let file = dir.path().join("file");
+        let normal = thumbnailed_file(&cache, &file);
+        let large = sized_thumbnail(&normal, "large", 32);
+        let broken = sized_thumbnail(&normal, "x-large", 64);
+        write(&broken, b"not a PNG file").unwrap();
+        // never the placeholder of a failed thumbnail
+        sized_thumbnail(&normal, "fail/app", 128);
+
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        let best = un.best_thumbnail(&file).unwrap().unwrap();
+        assert_eq!(best.thumbnail, large);
+    }
+
+    #[test]
+    fn export_to_directory() {
+        let dir = TempDir::new("export-dir");
+        let cache = dir.path().join("cache");
+        let files = [dir.path().join("a/x.txt"), dir.path().join("b/x.txt")];
+        for file in &files {
+            create_dir_all(file.parent().unwrap()).unwrap();
+            thumbnailed_file(&cache, file);
+        }
+        let to = dir.path().join("exported");
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        let exported = un
+            .export(&files, ExportTarget::Dir(&to), false, false)
+            .unwrap();
+        let names: Vec<_> = exported.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["x.txt.png", "x-2.txt.png"]);
+        assert!(to.join("x-2.txt.png").exists());
+
+        // existing files are only overwritten when asked to
+        write(to.join("x.txt.png"), b"kept").unwrap();
+        let exported = un
+            .export(&files[..1], ExportTarget::Dir(&to), false, false)
+            .unwrap();
+        assert!(exported.is_empty());
+        assert_eq!(read(to.join("x.txt.png")).unwrap(), b"kept");
+        let exported = un
+            .export(&files[..1], ExportTarget::Dir(&to), false, true)
+            .unwrap();
+        assert_eq!(exported.len(), 1);
+        assert_ne!(read(to.join("x.txt.png")).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn export_to_archive() {
+        let dir = TempDir::new("export-tar");
+        let cache = dir.path().join("cache");
+        let file = dir.path().join("file");
+        thumbnailed_file(&cache, &file);
+        let missing = dir.path().join("missing");
+        let archive = dir.path().join("thumbnails.tar");
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        let files = [file, missing];
+        let exported = un
+            .export(&files, ExportTarget::Tar(&archive), true, false)
+            .unwrap();
+        assert_eq!(exported.len(), 1);
+        let mut tar = tar::Archive::new(File::open(&archive).unwrap());
+        let names: Vec<_> = tar
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert_eq!(names, [PathBuf::from("file.png")]);
+
+        assert!(un
+            .export(&files, ExportTarget::Tar(&archive), true, false)
+            .is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::journal::JournalAction;
+use crate::png::Image;
+use crate::{read_metadata, uri_file_name, CacheEntry, Error, Thumbnail, UnThumbnailer};
+use anyhow::{Context, Result};
+use log::*;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{read, rename, set_permissions, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Non-PNG files more recent than this may still be written by a thumbnailer.
+const STRAY_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// A problem with an entry of the thumbnail cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Problem {
+    /// The thumbnail is truncated or isn't a valid PNG image.
+    Corrupt { error: String },
+    /// The thumbnail has no `Thumb::URI`.
+    MissingUri,
+    /// The thumbnail has no `Thumb::MTime`.
+    MissingMtime,
+    /// The name of the thumbnail isn't the MD5 hash of its `Thumb::URI`.
+    NameMismatch { expected: String },
+    /// The thumbnail isn't only readable and writable by its owner.
+    Permissions { mode: u32 },
+    /// The thumbnail is larger than its size class allows.
+    TooLarge { width: u32, height: u32, max: u32 },
+    /// A file that isn't a thumbnail, like a leftover temporary file.
+    Stray,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Corrupt { error } => write!(f, "corrupt image ({})", error),
+            Problem::MissingUri => write!(f, "missing Thumb::URI"),
+            Problem::MissingMtime => write!(f, "missing Thumb::MTime"),
+            Problem::NameMismatch { expected } => {
+                write!(f, "file name doesn't match its URI (expected {})", expected)
+            }
+            Problem::Permissions { mode } => write!(f, "permissions are {:o} instead of 600", mode),
+            Problem::TooLarge { width, height, max } => {
+                write!(f, "{}x{} is larger than {}x{}", width, height, max, max)
+            }
+            Problem::Stray => write!(f, "not a thumbnail"),
+        }
+    }
+}
+
+/// A problem found in the file at `path`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsckIssue {
+    pub path: PathBuf,
+    pub problem: Problem,
+    /// Whether the problem was fixed (by deleting, renaming or changing the
+    /// permissions of the file).
+    pub repaired: bool,
+}
+
+impl UnThumbnailer {
+    /// Check every entry of the thumbnail cache for problems, and fix them if
+    /// `repair` is set.
+    ///
+    /// Invalid thumbnails and stray files are deleted, thumbnails with a wrong
+    /// name are renamed and thumbnails with wrong permissions are made private.
+    pub fn fsck(&self, repair: bool) -> Result<Vec<FsckIssue>, Error> {
+        let mut issues = Vec::new();
+        for entry in self.scan_all() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    debug!("{}", e);
+                    continue;
+                }
+            };
+            let path = entry.thumbnail();
+            trace!("Processing {:?}", path);
+            let max_size = path.parent().and_then(size_class);
+            let problems = match check_entry(path, max_size) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("{} for {}", e, path.to_string_lossy());
+                    continue;
+                }
+            };
+            if problems.is_empty() {
+                continue;
+            }
+
+            let repaired = repair && {
+                match repair_entry(path, &problems, self) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("{:#}", e);
+                        false
+                    }
+                }
+            };
+            for problem in problems {
+                issues.push(FsckIssue {
+                    path: path.to_path_buf(),
+                    problem,
+                    repaired,
+                });
+            }
+        }
+
+        Ok(issues)
+    }
+}
+
+/// The maximum width and height of the thumbnails in the cache directory `location`,
+/// if it is a size class.
+fn size_class(location: &Path) -> Option<u32> {
+    match location.file_name()?.to_str()? {
+        "normal" => Some(128),
+        "large" => Some(256),
+        "x-large" => Some(512),
+        "xx-large" => Some(1024),
+        _ => None,
+    }
+}
+
+fn check_entry(path: &Path, max_size: Option<u32>) -> Result<Vec<Problem>> {
+    if path.extension().is_none_or(|e| e != "png") {
+        let age = path
+            .metadata()?
+            .modified()
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok());
+        // don't report temporary files that are still being written
+        return Ok(match age {
+            Some(age) if age < STRAY_MIN_AGE => vec![],
+            _ => vec![Problem::Stray],
+        });
+    }
+
+    let mut problems = Vec::new();
+    let mode = path.metadata()?.permissions().mode() & 0o777;
+    if mode != 0o600 {
+        problems.push(Problem::Permissions { mode });
+    }
+
+    let data = read(path)?;
+    let image = match Image::decode(&data) {
+        Ok(i) => i,
+        Err(e) => {
+            problems.push(Problem::Corrupt {
+                error: e.to_string(),
+            });
+            return Ok(problems);
+        }
+    };
+    if let Some(max) = max_size {
+        if image.width > max || image.height > max {
+            problems.push(Problem::TooLarge {
+                width: image.width,
+                height: image.height,
+                max,
+            });
+        }
+    }
+
+    let meta = read_metadata(data.as_slice())?;
+    if meta.mtime.is_none() {
+        problems.push(Problem::MissingMtime);
+    }
+    match meta.uri {
+        None => problems.push(Problem::MissingUri),
+        Some(uri) => {
+            let expected = uri_file_name(&uri);
+            if path.file_name().is_none_or(|n| n != expected.as_str()) {
+                problems.push(Problem::NameMismatch { expected });
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+fn repair_entry(path: &Path, problems: &[Problem], un: &UnThumbnailer) -> Result<()> {
+    let delete = problems.iter().any(|p| {
+        !matches!(
+            p,
+            Problem::NameMismatch { .. } | Problem::Permissions { .. }
+        )
+    });
+    if delete {
+        info!("Deleting {}", path.to_string_lossy());
+        return un
+            .remove_thumbnail(&cache_thumbnail(path))
+            .with_context(|| format!("Failed to delete {}", path.to_string_lossy()));
+    }
+
+    let mut path = path.to_path_buf();
+    for problem in problems {
+        match problem {
+            Problem::Permissions { .. } => {
+                info!("Making {} private", path.to_string_lossy());
+                let changed = set_permissions(&path, Permissions::from_mode(0o600));
+                un.record(JournalAction::Chmod, &path, None, None, &changed);
+                changed.with_context(|| {
+                    format!("Failed to change permissions of {}", path.to_string_lossy())
+                })?;
+            }
+            Problem::NameMismatch { expected } => {
+                let dest = path.with_file_name(expected);
+                if dest.exists() {
+                    // the right thumbnail is already there
+                    info!("Deleting duplicate {}", path.to_string_lossy());
+                    return un
+                        .remove_thumbnail(&cache_thumbnail(&path))
+                        .with_context(|| format!("Failed to delete {}", path.to_string_lossy()));
+                }
+                info!(
+                    "Renaming {} to {}",
+                    path.to_string_lossy(),
+                    dest.to_string_lossy()
+                );
+                let renamed = rename(&path, &dest);
+                un.record(JournalAction::Rename, &path, None, Some(&dest), &renamed);
+                renamed.with_context(|| format!("Failed to rename {}", path.to_string_lossy()))?;
+                path = dest;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The entry of the cache at `path` as a thumbnail, to delete it. Its file is
+/// left empty if the entry doesn't tell (like a corrupt image or a stray file).
+fn cache_thumbnail(path: &Path) -> Thumbnail {
+    let entry = CacheEntry::new(path.to_path_buf(), None);
+    Thumbnail {
+        file: entry.file().ok().flatten().unwrap_or_default(),
+        app: entry.app().map(Into::into),
+        thumbnail: path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::JournalAction;
+    use crate::quarantine::Quarantine;
+    use crate::testutil::{enable_journal, journal_actions, thumbnailed_file, TempDir};
+    use std::fs::{copy, write, File};
+
+    /// A valid thumbnail for the file `name` in the directory `dir`.
+    fn valid_thumbnail(dir: &Path, name: &str) -> PathBuf {
+        let thumb = thumbnailed_file(&dir.join("cache"), &dir.join(name));
+        set_permissions(&thumb, Permissions::from_mode(0o600)).unwrap();
+        thumb
+    }
+
+    fn problems(un: &UnThumbnailer, repair: bool) -> Vec<(PathBuf, Problem, bool)> {
+        un.fsck(repair)
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.path, i.problem, i.repaired))
+            .collect()
+    }
+
+    #[test]
+    fn check_and_repair() {
+        let dir = TempDir::new("fsck");
+        let valid = valid_thumbnail(dir.path(), "valid");
+        let public = valid_thumbnail(dir.path(), "public");
+        set_permissions(&public, Permissions::from_mode(0o644)).unwrap();
+        let normal = valid.parent().unwrap();
+        let corrupt = normal.join("corrupt.png");
+        write(&corrupt, b"not a PNG file").unwrap();
+        set_permissions(&corrupt, Permissions::from_mode(0o600)).unwrap();
+        // leftovers are only reported once old enough
+        let stray = normal.join("stray.tmp");
+        let recent = normal.join("recent.tmp");
+        write(&stray, b"").unwrap();
+        write(&recent, b"").unwrap();
+        File::options()
+            .write(true)
+            .open(&stray)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * STRAY_MIN_AGE)
+            .unwrap();
+        let mut un =
+            UnThumbnailer::with_cache_dir(false, false, false, &dir.path().join("cache")).unwrap();
+        enable_journal(&mut un, dir.path());
+
+        let found = problems(&un, false);
+        let problem_of = |path: &Path| {
+            found
+                .iter()
+                .find(|(p, _, _)| p == path)
+                .map(|(_, problem, _)| problem.clone())
+        };
+        assert_eq!(found.len(), 3);
+        assert!(found.iter().all(|(_, _, repaired)| !repaired));
+        assert!(matches!(
+            problem_of(&corrupt),
+            Some(Problem::Corrupt { .. })
+        ));
+        assert_eq!(
+            problem_of(&public),
+            Some(Problem::Permissions { mode: 0o644 })
+        );
+        assert_eq!(problem_of(&stray), Some(Problem::Stray));
+        assert!(journal_actions(&un).is_empty());
+
+        assert!(problems(&un, true).iter().all(|(_, _, repaired)| *repaired));
+        assert!(!corrupt.exists() && !stray.exists());
+        assert!(valid.exists() && recent.exists());
+        let mode = public.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+        let mut actions: Vec<_> = journal_actions(&un).into_iter().map(|(a, _)| a).collect();
+        actions.sort_by_key(|a| a.to_string());
+        assert_eq!(
+            actions,
+            [
+                JournalAction::Chmod,
+                JournalAction::Delete,
+                JournalAction::Delete
+            ]
+        );
+        assert!(problems(&un, false).is_empty());
+    }
+
+    #[test]
+    fn repair_mismatched_names() {
+        let dir = TempDir::new("fsck-names");
+        let valid = valid_thumbnail(dir.path(), "valid");
+        let normal = valid.parent().unwrap();
+        let duplicate = normal.join("duplicate.png");
+        copy(&valid, &duplicate).unwrap();
+        let other = valid_thumbnail(dir.path(), "other");
+        let misnamed = normal.join("misnamed.png");
+        rename(&other, &misnamed).unwrap();
+        let un =
+            UnThumbnailer::with_cache_dir(false, false, false, &dir.path().join("cache")).unwrap();
+
+        let found = problems(&un, true);
+        assert_eq!(found.len(), 2);
+        assert!(found
+            .iter()
+            .all(|(_, p, repaired)| matches!(p, Problem::NameMismatch { .. }) && *repaired));
+        assert!(!duplicate.exists() && !misnamed.exists());
+        assert!(valid.exists() && other.exists());
+    }
+
+    #[test]
+    fn repair_to_quarantine() {
+        let dir = TempDir::new("fsck-quarantine");
+        let valid = valid_thumbnail(dir.path(), "valid");
+        let corrupt = valid.with_file_name("corrupt.png");
+        write(&corrupt, b"not a PNG file").unwrap();
+        let mut un =
+            UnThumbnailer::with_cache_dir(false, false, false, &dir.path().join("cache")).unwrap();
+        un.quarantine = Some(Quarantine::new(dir.path().join("quarantine")));
+
+        problems(&un, true);
+        assert!(!corrupt.exists());
+        let results = un.undo().unwrap().unwrap();
+        assert_eq!(results.restored.len(), 1);
+        assert!(corrupt.exists());
+    }
+}
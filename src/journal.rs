@@ -0,0 +1,355 @@
+use crate::{Error, UnThumbnailer};
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Path of the journal, relative to the state directory.
+const JOURNAL_PATH: &str = "thumbs/journal.jsonl";
+
+/// A change made to the thumbnail cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalAction {
+    Delete,
+    Quarantine,
+    Restore,
+    Move,
+    Copy,
+    Rename,
+    Chmod,
+}
+
+impl fmt::Display for JournalAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalAction::Delete => write!(f, "delete"),
+            JournalAction::Quarantine => write!(f, "quarantine"),
+            JournalAction::Restore => write!(f, "restore"),
+            JournalAction::Move => write!(f, "move"),
+            JournalAction::Copy => write!(f, "copy"),
+            JournalAction::Rename => write!(f, "rename"),
+            JournalAction::Chmod => write!(f, "chmod"),
+        }
+    }
+}
+
+/// An entry of the journal of the changes made to the thumbnail cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// RFC 3339 timestamp
+    pub time: String,
+    /// The command line of the thumbs invocation that made the change
+    pub command: String,
+    pub action: JournalAction,
+    /// The file of the thumbnail, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    pub thumbnail: PathBuf,
+    /// Where the thumbnail was moved or copied to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<PathBuf>,
+    /// `ok`, or the error that made the change fail
+    pub result: String,
+}
+
+/// The journal file, appended to for each change.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    command: String,
+    /// Opened on the first change.
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    /// The journal of the user with the home directory `home`, for the same
+    /// command.
+    ///
+    /// The `XDG_STATE_HOME` of another user isn't known, so this is always in
+    /// its default location, `~/.local/state`.
+    pub(crate) fn for_user(&self, home: &Path) -> Self {
+        Journal {
+            path: home.join(".local/state").join(JOURNAL_PATH),
+            command: self.command.clone(),
+            file: Mutex::new(None),
+        }
+    }
+
+    fn write(&self, entry: &JournalEntry) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(parent)?;
+            }
+            *file = Some(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(0o600)
+                    .open(&self.path)?,
+            );
+        }
+
+        let file = file.as_mut().unwrap();
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Journal {
+    /// A journal at `path`, instead of the default one.
+    pub(crate) fn at(path: PathBuf) -> Self {
+        Journal {
+            path,
+            command: String::new(),
+            file: Mutex::new(None),
+        }
+    }
+}
+
+/// The default journal, in `$XDG_STATE_HOME/thumbs/journal.jsonl`.
+fn journal_path() -> Result<PathBuf> {
+    let mut path = dirs::state_dir().ok_or(Error::NoDirectory("state"))?;
+    path.push(JOURNAL_PATH);
+    Ok(path)
+}
+
+impl UnThumbnailer {
+    /// Record every change made to the thumbnail cache in a journal, in
+    /// `$XDG_STATE_HOME/thumbs/journal.jsonl`, along with the `command` making
+    /// them.
+    pub fn enable_journal(&mut self, command: &str) -> Result<(), Error> {
+        self.journal = Some(Journal {
+            path: journal_path()?,
+            command: command.to_string(),
+            file: Mutex::new(None),
+        });
+
+        Ok(())
+    }
+
+    /// Record the result of the change `action` to the thumbnail at `thumbnail`,
+    /// if the journal is enabled.
+    pub(crate) fn record<T, E: fmt::Display>(
+        &self,
+        action: JournalAction,
+        thumbnail: &Path,
+        file: Option<&Path>,
+        destination: Option<&Path>,
+        result: &std::result::Result<T, E>,
+    ) {
+        let journal = match &self.journal {
+            Some(j) => j,
+            None => return,
+        };
+
+        let entry = JournalEntry {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            command: journal.command.clone(),
+            action,
+            file: file.map(Path::to_path_buf),
+            thumbnail: thumbnail.to_path_buf(),
+            destination: destination.map(Path::to_path_buf),
+            result: match result {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("{:#}", e),
+            },
+        };
+        if let Err(e) = journal.write(&entry) {
+            warn!(
+                "Failed to write to journal {}: {}",
+                journal.path.to_string_lossy(),
+                e
+            );
+        }
+    }
+
+    /// Read the entries of the journal, optionally only the ones between `since`
+    /// and `until`, or for files or thumbnails under `path`.
+    pub fn history(
+        &self,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        path: Option<&Path>,
+    ) -> Result<Vec<JournalEntry>, Error> {
+        let journal = match &self.journal {
+            Some(j) => j.path.clone(),
+            None => journal_path()?,
+        };
+        let file = match File::open(&journal) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(Error::Io {
+                    context: Some(format!("Failed to read {}", journal.to_string_lossy())),
+                    source: e,
+                })
+            }
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let entry: JournalEntry = match serde_json::from_str(&line) {
+                Ok(e) => e,
+                Err(e) => {
+                    debug!("Ignoring invalid journal entry '{}': {}", line, e);
+                    continue;
+                }
+            };
+
+            if since.is_some() || until.is_some() {
+                let time = match humantime::parse_rfc3339_weak(&entry.time) {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                if since.is_some_and(|s| time < s) || until.is_some_and(|u| time > u) {
+                    continue;
+                }
+            }
+            if let Some(path) = path {
+                let matches = entry.file.as_deref().is_some_and(|f| f.starts_with(path))
+                    || entry.thumbnail.starts_with(path)
+                    || entry
+                        .destination
+                        .as_deref()
+                        .is_some_and(|d| d.starts_with(path));
+                if !matches {
+                    continue;
+                }
+            }
+
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs::write;
+
+    /// An `UnThumbnailer` with a journal holding entries at the given times, for
+    /// the thumbnails of the given files.
+    fn journal(dir: &Path, entries: &[(&str, &str)]) -> UnThumbnailer {
+        let path = dir.join("journal.jsonl");
+        let mut lines: Vec<_> = entries
+            .iter()
+            .map(|(time, file)| {
+                serde_json::to_string(&JournalEntry {
+                    time: time.to_string(),
+                    command: "thumbs delete".to_string(),
+                    action: JournalAction::Delete,
+                    file: Some(PathBuf::from(file)),
+                    thumbnail: PathBuf::from(format!("/cache/normal/{}.png", time.len())),
+                    destination: None,
+                    result: "ok".to_string(),
+                })
+                .unwrap()
+            })
+            .collect();
+        lines.push("not an entry".to_string());
+        write(&path, lines.join("\n")).unwrap();
+
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, dir).unwrap();
+        un.journal = Some(Journal::at(path));
+        un
+    }
+
+    fn files(entries: Vec<JournalEntry>) -> Vec<PathBuf> {
+        entries.into_iter().filter_map(|e| e.file).collect()
+    }
+
+    fn time(t: &str) -> Option<SystemTime> {
+        Some(humantime::parse_rfc3339_weak(t).unwrap())
+    }
+
+    #[test]
+    fn history_between() {
+        let dir = TempDir::new("journal-time");
+        let un = journal(
+            dir.path(),
+            &[
+                ("2022-01-01T10:00:00Z", "/a"),
+                ("2022-01-02T10:00:00Z", "/b"),
+                ("2022-01-03T10:00:00Z", "/c"),
+            ],
+        );
+
+        assert_eq!(files(un.history(None, None, None).unwrap()).len(), 3);
+        assert_eq!(
+            files(un.history(time("2022-01-02 10:00:00"), None, None).unwrap()),
+            vec![PathBuf::from("/b"), PathBuf::from("/c")]
+        );
+        assert_eq!(
+            files(un.history(None, time("2022-01-02 09:00:00"), None).unwrap()),
+            vec![PathBuf::from("/a")]
+        );
+        assert_eq!(
+            files(
+                un.history(
+                    time("2022-01-02 00:00:00"),
+                    time("2022-01-03 00:00:00"),
+                    None
+                )
+                .unwrap()
+            ),
+            vec![PathBuf::from("/b")]
+        );
+    }
+
+    #[test]
+    fn history_of_path() {
+        let dir = TempDir::new("journal-path");
+        let un = journal(
+            dir.path(),
+            &[
+                ("2022-01-01T10:00:00Z", "/photos/a.jpg"),
+                ("2022-01-02T10:00:00Z", "/photos-old/b.jpg"),
+                ("2022-01-03T10:00:00Z", "/videos/c.mkv"),
+            ],
+        );
+
+        assert_eq!(
+            files(un.history(None, None, Some(Path::new("/photos"))).unwrap()),
+            vec![PathBuf::from("/photos/a.jpg")]
+        );
+        // thumbnails match too
+        assert_eq!(
+            un.history(None, None, Some(Path::new("/cache/normal")))
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(un
+            .history(None, None, Some(Path::new("/music")))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn missing_history() {
+        let dir = TempDir::new("journal-missing");
+        let mut un = journal(dir.path(), &[]);
+        if let Some(j) = un.journal.as_mut() {
+            j.path = dir.path().join("missing.jsonl");
+        }
+
+        assert!(un.history(None, None, None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+use crate::backup::is_unmodified;
+use crate::journal::JournalAction;
+use crate::png::{self, Image};
+use crate::{find_cache_locations, read_metadata, uri_file_name, Thumbnail, UnThumbnailer};
+use crate::{Error, MovedThumbnail, ThumbnailMetadata};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{read, remove_dir, remove_file, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use url::Url;
+use walkdir::WalkDir;
+
+/// The thumbnail cache used before the XDG base directories, relative to the home
+/// directory.
+const LEGACY_CACHE: &str = ".thumbnails";
+
+#[derive(Debug, Default, Serialize)]
+pub struct MigrateResults {
+    pub migrated: Vec<MovedThumbnail>,
+    /// Thumbnails that are invalid, or whose file is missing or was modified.
+    pub invalid: u32,
+    /// Thumbnails that are already in the cache, with the same or a more recent
+    /// version.
+    pub existing: u32,
+}
+
+impl UnThumbnailer {
+    /// Also look for thumbnails in the legacy cache (`~/.thumbnails`) of the user
+    /// with the home directory `home`.
+    pub fn add_legacy_cache(&mut self, home: &Path) -> Result<(), Error> {
+        if let Some(legacy) = self.legacy_dir(home) {
+            debug!(
+                "Found a legacy thumbnail cache in {}",
+                legacy.to_string_lossy()
+            );
+            self.cache_locs.extend(find_cache_locations(&legacy)?);
+        }
+        self.legacy = true;
+
+        Ok(())
+    }
+
+    /// Move the valid thumbnails of the legacy cache (`~/.thumbnails`) of the
+    /// user with the home directory `home` to the cache, and remove the legacy
+    /// cache. `dry_run` only reports results but doesn't actually move or
+    /// remove anything.
+    ///
+    /// If a thumbnail can't be read or written, the legacy cache is left in
+    /// place and an error is returned. Otherwise, only the thumbnails that were
+    /// moved, skipped or found invalid are removed with it, along with the
+    /// directories left empty.
+    ///
+    /// Only thumbnails of files that still exist with the same modification time
+    /// are moved, and only if the cache doesn't have the same or a more recent
+    /// thumbnail.
+    pub fn migrate_legacy(&self, home: &Path, dry_run: bool) -> Result<MigrateResults, Error> {
+        let mut results = MigrateResults::default();
+        let legacy = match self.legacy_dir(home) {
+            Some(l) => l,
+            None => {
+                debug!("Found no legacy cache in {}", home.to_string_lossy());
+                return Ok(results);
+            }
+        };
+
+        // the entries that can go away with the legacy cache
+        let mut processed = Vec::new();
+        let mut failed = 0;
+        for entry in WalkDir::new(&legacy)
+            .min_depth(2)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.file_type().is_dir() && e.path().extension().is_some_and(|p| p == "png"))
+        {
+            let dest = self.cache_dir.join(entry.path().strip_prefix(&legacy)?);
+            match migrate_thumbnail(entry.path(), &dest, dry_run, self) {
+                Ok(Migration::Moved(m)) => results.migrated.push(m),
+                Ok(Migration::Existing) => results.existing += 1,
+                Ok(Migration::Invalid(e)) => {
+                    debug!("{} for {}", e, entry.path().to_string_lossy());
+                    results.invalid += 1;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to migrate {}: {:#}",
+                        entry.path().to_string_lossy(),
+                        e
+                    );
+                    failed += 1;
+                    continue;
+                }
+            }
+            processed.push(entry.into_path());
+        }
+
+        if failed != 0 {
+            return Err(anyhow!(
+                "Failed to migrate {} legacy thumbnail(s), {} was left in place",
+                failed,
+                legacy.to_string_lossy()
+            )
+            .into());
+        }
+        if dry_run {
+            info!("Would remove {}", legacy.to_string_lossy());
+        } else {
+            info!("Removing {}", legacy.to_string_lossy());
+            remove_processed(&legacy, &processed, &results.migrated, self)
+                .with_context(|| format!("Failed to remove {}", legacy.to_string_lossy()))?;
+            if legacy.exists() {
+                warn!(
+                    "Left {} in place, it contains other files.",
+                    legacy.to_string_lossy()
+                );
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// The legacy cache of the user with the home directory `home`, unless it
+    /// doesn't exist or is a link to the cache.
+    fn legacy_dir(&self, home: &Path) -> Option<PathBuf> {
+        let legacy = home.join(LEGACY_CACHE);
+        if !legacy.is_dir() || legacy.canonicalize().ok() == self.cache_dir.canonicalize().ok() {
+            return None;
+        }
+
+        Some(legacy)
+    }
+}
+
+enum Migration {
+    Moved(MovedThumbnail),
+    Existing,
+    /// The thumbnail is invalid, or its file is missing or was modified.
+    Invalid(anyhow::Error),
+}
+
+/// Remove the `processed` entries of the legacy cache, and then its directories
+/// if they are left empty.
+///
+/// The removal of the thumbnails in `migrated` completes their move, and is
+/// recorded as such. The other entries are recorded as deleted.
+fn remove_processed(
+    legacy: &Path,
+    processed: &[PathBuf],
+    migrated: &[MovedThumbnail],
+    un: &UnThumbnailer,
+) -> io::Result<()> {
+    let migrated: HashMap<_, _> = migrated
+        .iter()
+        .map(|m| (m.from.thumbnail.as_path(), m))
+        .collect();
+    for path in processed {
+        let removed = match remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        };
+        match migrated.get(path.as_path()) {
+            Some(m) => un.record(
+                JournalAction::Move,
+                path,
+                Some(&m.from.file),
+                Some(&m.to.thumbnail),
+                &removed,
+            ),
+            None => un.record(JournalAction::Delete, path, None, None, &removed),
+        }
+        removed?;
+    }
+    for entry in WalkDir::new(legacy)
+        .contents_first(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+    {
+        // fails for directories with other files in them, which are kept
+        let _ = remove_dir(entry.path());
+    }
+
+    Ok(())
+}
+
+fn migrate_thumbnail(
+    path: &Path,
+    dest: &Path,
+    dry_run: bool,
+    un: &UnThumbnailer,
+) -> Result<Migration> {
+    trace!("Processing {:?}", path);
+    let data = read(path)?;
+    let (file, mtime) = match check_thumbnail(path, &data) {
+        Ok(checked) => checked,
+        Err(e) => return Ok(Migration::Invalid(e)),
+    };
+
+    if dest.exists() {
+        let existing = read(dest)
+            .map_err(Into::into)
+            .and_then(|d| read_metadata(d.as_slice()));
+        if existing.is_ok_and(|m| m.mtime >= Some(mtime)) {
+            debug!(
+                "Skipping a legacy thumbnail for '{}' already in the cache",
+                file.to_string_lossy()
+            );
+            return Ok(Migration::Existing);
+        }
+    }
+
+    if dry_run {
+        info!(
+            "Would move a legacy thumbnail for '{}'",
+            file.to_string_lossy()
+        );
+    } else {
+        info!("Moving a legacy thumbnail for '{}'", file.to_string_lossy());
+        if let Some(parent) = dest.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        let written = png::write_thumbnail(dest, &data);
+        // the move is recorded once the legacy thumbnail is removed
+        if written.is_err() {
+            un.record(JournalAction::Move, path, Some(&file), Some(dest), &written);
+        }
+        written?;
+    }
+
+    Ok(Migration::Moved(MovedThumbnail {
+        from: Thumbnail {
+            thumbnail: path.to_path_buf(),
+            file: file.clone(),
+            app: None,
+        },
+        to: Thumbnail {
+            thumbnail: dest.to_path_buf(),
+            file,
+            app: None,
+        },
+    }))
+}
+
+/// Check that the data of a legacy thumbnail is a valid thumbnail for a file
+/// that wasn't modified since. Returns the file and its modification time.
+fn check_thumbnail(path: &Path, data: &[u8]) -> Result<(PathBuf, u64)> {
+    Image::decode(data)?;
+    let meta = read_metadata(data)?;
+    check_metadata(path, &meta)
+}
+
+/// Check that the thumbnail at `path` is named after its URI, and that its file
+/// wasn't modified since. Returns the file and its modification time.
+fn check_metadata(path: &Path, meta: &ThumbnailMetadata) -> Result<(PathBuf, u64)> {
+    let uri = meta
+        .uri
+        .as_ref()
+        .ok_or_else(|| anyhow!("failed to find origin path"))?;
+    let mtime = meta
+        .mtime
+        .ok_or_else(|| anyhow!("failed to find origin modification time"))?;
+    if path
+        .file_name()
+        .is_none_or(|n| n != uri_file_name(uri).as_str())
+    {
+        return Err(anyhow!("file name doesn't match origin path"));
+    }
+
+    let file = Url::parse(uri)
+        .ok()
+        .and_then(|u| u.to_file_path().ok())
+        .ok_or_else(|| anyhow!("ignoring origin URI {}", uri))?;
+    if !is_unmodified(&file, mtime) {
+        return Err(anyhow!("origin file was deleted or modified"));
+    }
+
+    Ok((file, mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{
+        enable_journal, journal_actions, thumbnail_path, thumbnailed_file, TempDir,
+    };
+    use std::fs::{copy, create_dir_all};
+
+    #[test]
+    fn migrate_legacy_thumbnails() {
+        let home = TempDir::new("legacy-migrate");
+        let home = home.path();
+        let legacy = home.join(LEGACY_CACHE);
+        let cache = home.join(".cache/thumbnails");
+        create_dir_all(&cache).unwrap();
+        let valid = home.join("valid");
+        let missing = home.join("missing");
+        let existing = home.join("existing");
+        let legacy_thumb = thumbnailed_file(&legacy, &valid);
+        thumbnailed_file(&legacy, &missing);
+        remove_file(&missing).unwrap();
+        let cached = thumbnailed_file(&cache, &existing);
+        create_dir_all(thumbnail_path(&legacy, &existing).parent().unwrap()).unwrap();
+        copy(&cached, thumbnail_path(&legacy, &existing)).unwrap();
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        enable_journal(&mut un, home);
+
+        let results = un.migrate_legacy(home, true).unwrap();
+        assert_eq!(results.migrated.len(), 1);
+        assert_eq!((results.invalid, results.existing), (1, 1));
+        assert!(legacy_thumb.exists());
+        assert!(!thumbnail_path(&cache, &valid).exists());
+        assert!(journal_actions(&un).is_empty());
+
+        let data = read(&legacy_thumb).unwrap();
+        let results = un.migrate_legacy(home, false).unwrap();
+        assert_eq!(results.migrated.len(), 1);
+        assert_eq!(results.migrated[0].from.thumbnail, legacy_thumb);
+        assert_eq!(results.migrated[0].to.file, valid);
+        assert_eq!(read(thumbnail_path(&cache, &valid)).unwrap(), data);
+        assert!(!legacy.exists());
+        let mut actions: Vec<_> = journal_actions(&un).into_iter().map(|(a, _)| a).collect();
+        actions.sort_by_key(|a| a.to_string());
+        assert_eq!(
+            actions,
+            [
+                JournalAction::Delete,
+                JournalAction::Delete,
+                JournalAction::Move
+            ]
+        );
+
+        // nothing left to migrate
+        let results = un.migrate_legacy(home, false).unwrap();
+        assert!(results.migrated.is_empty());
+    }
+}
@@ -1,100 +1,160 @@
 use anyhow::{anyhow, format_err, Context, Result};
 use globset::{Candidate, GlobSet};
+use journal::Journal;
 use log::*;
 use png_pong::{chunk::Chunk, Decoder};
-use std::fs::{remove_file, File};
+use quarantine::Quarantine;
+use scan::{CancelCallback, ProgressCallback};
+use serde::Serialize;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use std::{ffi::OsStr, io::BufReader, os::unix::prelude::OsStrExt};
+use std::{ffi::OsStr, io::Read, os::unix::prelude::OsStrExt};
 use url::Url;
-use walkdir::{DirEntry, WalkDir};
+use walkdir::WalkDir;
 
+mod backup;
+mod checkpoint;
 pub mod cli;
+pub mod config;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(not(feature = "dbus"))]
+#[path = "no_dbus.rs"]
+pub mod dbus;
+mod error;
+mod export;
+mod fsck;
+mod journal;
+mod legacy;
+mod montage;
+mod png;
+pub mod preview;
+pub mod progress;
+pub mod prompt;
+mod quarantine;
+mod relocate;
+mod sandbox;
+mod scan;
+mod stats;
+#[cfg(test)]
+mod testutil;
+mod users;
+mod watch;
+
+pub use backup::RestoreResults;
+pub use error::Error;
+pub use export::{ExportTarget, ExportedThumbnail};
+pub use fsck::{FsckIssue, Problem};
+pub use journal::{JournalAction, JournalEntry};
+pub use legacy::MigrateResults;
+pub use quarantine::UndoResults;
+pub use relocate::MovedThumbnail;
+pub use sandbox::{AppCache, Sandbox};
+pub use scan::{Action, CacheEntry, FileScan, Progress, Scan};
+pub use stats::CacheStats;
+pub use users::{User, UserCleanupResults};
+pub use watch::WatchEvent;
 
 #[derive(Debug)]
 pub struct UnThumbnailer {
     pub recursive: bool,
     pub hidden: bool,
+    pub respect_ignore: bool,
+    cache_dir: PathBuf,
     cache_locs: Vec<PathBuf>,
+    app_caches: Vec<AppCache>,
+    /// The applications selected with `add_app_caches`, if it was used.
+    apps: Option<Vec<String>>,
+    /// Whether `add_legacy_cache` was used.
+    legacy: bool,
+    quarantine: Option<Quarantine>,
+    journal: Option<Journal>,
+    progress: Option<ProgressCallback>,
+    cancel: Option<CancelCallback>,
 }
 
 impl UnThumbnailer {
-    pub fn new(recursive: bool, hidden: bool) -> Result<Self> {
-        let cache_locs = find_cache_locations()?;
+    /// Uses the thumbnail cache in `$XDG_CACHE_HOME/thumbnails`, and the legacy
+    /// cache in `~/.thumbnails`.
+    pub fn new(recursive: bool, hidden: bool, respect_ignore: bool) -> Result<Self, Error> {
+        let mut cache = dirs::cache_dir().ok_or(Error::NoCacheDirectory)?;
+        cache.push("thumbnails/");
+
+        let mut un = Self::with_cache_dir(recursive, hidden, respect_ignore, &cache)?;
+        if let Some(home) = dirs::home_dir() {
+            un.add_legacy_cache(&home)?;
+        }
+
+        Ok(un)
+    }
+
+    /// Same as `new`, but uses the thumbnail cache at `cache` instead of the
+    /// default ones.
+    pub fn with_cache_dir(
+        recursive: bool,
+        hidden: bool,
+        respect_ignore: bool,
+        cache: &Path,
+    ) -> Result<Self, Error> {
+        let cache_locs = find_cache_locations(cache)?;
         Ok(Self {
             recursive,
             hidden,
+            respect_ignore,
+            cache_dir: cache.to_path_buf(),
             cache_locs,
+            app_caches: Vec::new(),
+            apps: None,
+            legacy: false,
+            quarantine: None,
+            journal: None,
+            progress: None,
+            cancel: None,
         })
     }
 
     /// Delete thumbnails for the files at `paths`, possibly recursing in directories
     /// if enabled. `dry_run` only reports results but doesn't actually delete
     /// anything.
+    ///
+    /// If `respect_ignore` is set, files matched by `.gitignore`, `.ignore` or
+    /// `.thumbsignore` files are skipped while walking directories.
+    ///
+    /// This is `scan_files` followed by the deleting action on each thumbnail.
     pub fn delete(
         &self,
         paths: &[PathBuf],
         dry_run: bool,
         last_accessed: Option<SystemTime>,
-    ) -> Result<DeleteResults> {
+    ) -> Result<DeleteResults, Error> {
         let mut thumbs = Vec::new();
-        let mut nb_ignore_dirs = 0;
+        let mut failed = Vec::new();
 
-        let mode = if dry_run { Mode::DryRun } else { Mode::Delete };
-
-        for path in paths.iter() {
-            if path.is_file() {
-                do_for_thumbnail(path, &self.cache_locs, &mut thumbs, mode)?;
-            } else {
-                let mut walk = WalkDir::new(path).min_depth(1);
-                if !self.recursive {
-                    walk = walk.max_depth(1);
-                }
-                for entry in walk
-                    .into_iter()
-                    .filter_entry(|e| self.hidden || !is_hidden_unix(e.file_name()))
-                    .filter_map(|e| e.ok())
-                {
-                    trace!("entry: {:?}", entry);
-                    if entry.file_type().is_dir() {
-                        if !self.recursive {
-                            nb_ignore_dirs += 1;
-                        }
-                    } else if let Some(last_accessed) = last_accessed {
-                        fn entry_was_accessed_since(e: &DirEntry, t: SystemTime) -> Result<bool> {
-                            let acc_t = e.metadata()?.accessed()?;
-
-                            Ok(acc_t >= t)
-                        }
-
-                        match entry_was_accessed_since(&entry, last_accessed) {
-                            Ok(false) => {
-                                do_for_thumbnail(
-                                    entry.path(),
-                                    &self.cache_locs,
-                                    &mut thumbs,
-                                    mode,
-                                )?;
-                            }
-                            Ok(true) => {}
-                            Err(e) => {
-                                debug!(
-                                    "Failed to find accesstime of {}",
-                                    entry.path().to_string_lossy()
-                                );
-                                trace!("Failed with {}", e);
-                            }
-                        }
-                    } else {
-                        do_for_thumbnail(entry.path(), &self.cache_locs, &mut thumbs, mode)?;
-                    }
+        let action = if dry_run {
+            Action::Report
+        } else {
+            self.delete_action()
+        };
+
+        let mut scan = self.scan_files(paths, last_accessed);
+        while let Some(entry) = scan.next() {
+            match entry.and_then(|e| e.to_thumbnail()) {
+                Ok(Some(th)) => delete_thumbnail(th, action, &mut thumbs, &mut failed, self),
+                Ok(None) => {}
+                // files can vanish while walking, keep going with the others
+                Err(e) => {
+                    let file = scan.file().map(Path::to_path_buf).unwrap_or_default();
+                    debug!("Failed for {}: {}", file.to_string_lossy(), e);
+                    failed.push(FailedThumbnail::for_file(file, &e));
                 }
             }
         }
 
         Ok(DeleteResults {
             thumbnail_paths: thumbs,
-            ignored_directories: nb_ignore_dirs,
+            ignored_directories: scan.ignored_directories(),
+            failed,
+            interrupted: scan.interrupted(),
         })
     }
 
@@ -103,10 +163,11 @@ impl UnThumbnailer {
     /// The path has to point to a file. Multiple results can be returned because
     /// multiple thumbnails with different sizes can be returned for the same
     /// source.
-    pub fn locate(&self, path: &Path) -> Result<Vec<Thumbnail>> {
+    pub fn locate(&self, path: &Path) -> Result<Vec<Thumbnail>, Error> {
         let mut thumbs = Vec::new();
-
-        do_for_thumbnail(path, &self.cache_locs, &mut thumbs, Mode::Locate)?;
+        for entry in self.thumbnails_of(path)? {
+            thumbs.extend(entry.to_thumbnail()?);
+        }
 
         Ok(thumbs)
     }
@@ -116,118 +177,274 @@ impl UnThumbnailer {
     /// The `exclude` and `include` globs constrain the search to thumbnails whose original
     /// files match them.
     ///
-    /// Returns the number of matching thumbnails.
+    /// Thumbnails of existing files whose name doesn't match their `Thumb::URI` are
+    /// also reported, and renamed if both `force` and `rename` are set.
+    ///
+    /// Progress is reported for each thumbnail of the cache.
     pub fn cleanup(
         &self,
         force: bool,
+        rename: bool,
         exclude: &GlobSet,
         include: &GlobSet,
-    ) -> Result<Vec<Thumbnail>> {
-        let mut thumbs = Vec::new();
-        for location in &self.cache_locs {
-            for entry in WalkDir::new(location)
-                .min_depth(1)
-                .into_iter()
-                .filter_entry(|e| self.hidden || !is_hidden_unix(e.file_name()))
-                .filter_map(|e| e.ok())
-                .filter(|e| {
-                    !e.file_type().is_dir() && e.path().extension().map_or(false, |p| p == "png")
-                })
-            {
-                match clean_thumbnail(entry.path(), force, exclude, include, &mut thumbs) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        if log_enabled!(log::Level::Trace) {
-                            trace!("{} for {}", e, entry.path().to_string_lossy());
-                        } else {
-                            debug!("{} for {}", e, entry.path().to_string_lossy());
-                        }
-                    }
-                };
+    ) -> Result<CleanupResults, Error> {
+        self.cleanup_after(force, rename, exclude, include, None)
+    }
+
+    /// Same as `cleanup`, but only for the thumbnails after the thumbnail at
+    /// `after` (see `scan_after`), to resume an interrupted cleanup from its
+    /// `resume_after` result.
+    pub fn cleanup_after(
+        &self,
+        force: bool,
+        rename: bool,
+        exclude: &GlobSet,
+        include: &GlobSet,
+        after: Option<&Path>,
+    ) -> Result<CleanupResults, Error> {
+        let mut results = CleanupResults::default();
+        // counting the thumbnails is cheap compared to reading them
+        let mut progress = Progress {
+            total: if self.wants_progress() {
+                Some(self.scan_after(after).count() as u64)
+            } else {
+                None
+            },
+            ..Progress::default()
+        };
+        let mut last = after.map(Path::to_path_buf);
+        for entry in self.scan_after(after) {
+            if self.cancelled() {
+                results.interrupted = true;
+                results.resume_after = last;
+                break;
             }
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    debug!("{}", e);
+                    continue;
+                }
+            };
+            match clean_thumbnail(
+                &entry,
+                force,
+                force && rename,
+                exclude,
+                include,
+                &mut results,
+                self,
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    if log_enabled!(log::Level::Trace) {
+                        trace!("{} for {}", e, entry.thumbnail().to_string_lossy());
+                    } else {
+                        debug!("{} for {}", e, entry.thumbnail().to_string_lossy());
+                    }
+                }
+            };
+            last = Some(entry.thumbnail().to_path_buf());
+            progress.done += 1;
+            progress.found = results.thumbnails.len() as u64;
+            self.report_progress(progress);
         }
+        progress.finished = true;
+        self.report_progress(progress);
 
-        Ok(thumbs)
+        Ok(results)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct DeleteResults {
     pub thumbnail_paths: Vec<Thumbnail>,
     pub ignored_directories: u32,
+    /// Thumbnails that couldn't be deleted, and files whose thumbnails couldn't
+    /// be looked for (with an empty `thumbnail` path).
+    pub failed: Vec<FailedThumbnail>,
+    /// Whether the deletion was stopped early by the callback of `cancel_when`.
+    pub interrupted: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CleanupResults {
+    /// Thumbnails of files that don't exist.
+    pub thumbnails: Vec<Thumbnail>,
+    /// Thumbnails whose name doesn't match their `Thumb::URI`, and the name
+    /// they should have.
+    pub mismatched: Vec<MovedThumbnail>,
+    /// Thumbnails of files that don't exist that couldn't be deleted.
+    pub failed: Vec<FailedThumbnail>,
+    /// Whether the cleanup was stopped early by the callback of `cancel_when`.
+    pub interrupted: bool,
+    /// When interrupted, the last cache entry processed, to resume the cleanup
+    /// after it with `cleanup_after`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_after: Option<PathBuf>,
+}
+
+/// A thumbnail that couldn't be deleted.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedThumbnail {
+    #[serde(flatten)]
+    pub thumbnail: Thumbnail,
+    pub reason: FailureReason,
+    /// The full error message.
+    pub error: String,
+}
+
+/// Why a thumbnail couldn't be deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureReason {
+    PermissionDenied,
+    /// The thumbnail was deleted by something else in the meantime.
+    Vanished,
+    /// The thumbnail is a directory or another kind of special file.
+    NotAFile,
+    Other,
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureReason::PermissionDenied => write!(f, "permission denied"),
+            FailureReason::Vanished => write!(f, "vanished"),
+            FailureReason::NotAFile => write!(f, "not a file"),
+            FailureReason::Other => write!(f, "error"),
+        }
+    }
+}
+
+impl FailedThumbnail {
+    /// A file whose thumbnails couldn't be looked for, with an empty
+    /// `thumbnail` path.
+    pub(crate) fn for_file(file: PathBuf, error: &Error) -> Self {
+        let reason = match error {
+            Error::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound => {
+                FailureReason::Vanished
+            }
+            Error::Io { source, .. } if source.kind() == std::io::ErrorKind::PermissionDenied => {
+                FailureReason::PermissionDenied
+            }
+            _ => FailureReason::Other,
+        };
+
+        FailedThumbnail {
+            thumbnail: Thumbnail {
+                thumbnail: PathBuf::new(),
+                file,
+                app: None,
+            },
+            reason,
+            error: error.to_string(),
+        }
+    }
+
+    pub(crate) fn new(thumbnail: Thumbnail, error: &anyhow::Error) -> Self {
+        let io_error = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<std::io::Error>());
+        let reason = match io_error {
+            Some(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                FailureReason::PermissionDenied
+            }
+            Some(e) if e.kind() == std::io::ErrorKind::NotFound => FailureReason::Vanished,
+            Some(e) if e.raw_os_error() == Some(libc::EISDIR) => FailureReason::NotAFile,
+            _ if !thumbnail.thumbnail.is_file() && thumbnail.thumbnail.exists() => {
+                FailureReason::NotAFile
+            }
+            _ => FailureReason::Other,
+        };
+
+        FailedThumbnail {
+            thumbnail,
+            reason,
+            error: format!("{:#}", error),
+        }
+    }
+}
+
+/// The results of `dbus::ThumbnailService::generate`.
+#[derive(Debug, Default, Serialize)]
+pub struct GenerateResults {
+    /// The files whose thumbnails were generated.
+    pub generated: Vec<PathBuf>,
+    pub failed: Vec<FailedFile>,
+}
+
+/// A file whose thumbnail couldn't be generated.
+#[derive(Debug, Serialize)]
+pub struct FailedFile {
+    pub file: PathBuf,
+    /// The error reported by the thumbnail service.
+    pub error: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Thumbnail {
     pub thumbnail: PathBuf,
     pub file: PathBuf,
+    /// The ID of the sandboxed application whose cache contains the thumbnail.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
 }
 
-#[derive(Copy, Clone)]
-enum Mode {
-    Locate,
-    DryRun,
-    Delete,
+/// Apply the deleting `action` to the thumbnail `th`, adding it to `thumbs` if
+/// it succeeds and to `failed` otherwise.
+fn delete_thumbnail(
+    th: Thumbnail,
+    action: Action,
+    thumbs: &mut Vec<Thumbnail>,
+    failed: &mut Vec<FailedThumbnail>,
+    un: &UnThumbnailer,
+) {
+    if action == Action::Report {
+        info!("Would delete a thumbnail for {}", th.file.to_string_lossy());
+    } else {
+        info!("Deleting a thumbnail for '{}'", th.file.to_string_lossy());
+
+        if let Err(e) = un.run_action(action, &th) {
+            debug!("Failed to delete {}: {}", th.thumbnail.to_string_lossy(), e);
+            failed.push(FailedThumbnail::new(th, &e));
+            return;
+        }
+    }
+    thumbs.push(th);
 }
 
-fn do_for_thumbnail(
-    path: &Path,
-    locations: &[PathBuf],
-    acc_paths: &mut Vec<Thumbnail>,
-    mode: Mode,
-) -> Result<()> {
+/// Build the `file://` URL for `path`, which is used to identify the thumbnails
+/// of a file.
+fn file_url(path: &Path) -> Result<Url> {
     // TODO is canonicalize too much? (it resolves symlinks)
-    let url = if !path.is_absolute() {
+    if !path.is_absolute() {
         Url::from_file_path(&path.canonicalize()?)
     } else {
-        Url::from_file_path(&path)
+        Url::from_file_path(path)
     }
-    .map_err(|_| format_err!("Non absolute path: {:?}", &path))?;
-    trace!("Url: {:?}", url);
-
-    let digest = md5::compute(url.as_str().as_bytes());
-
-    debug!("Processing {:?} ({:x})", path, digest);
-
-    let mut thumb_seen = false;
-
-    for location in locations.iter() {
-        let mut thumb = location.clone();
-        thumb.push(format!("{:x}", digest));
-        thumb.set_extension("png");
-        if thumb.exists() {
-            debug!("  Found      {:?}", thumb);
-            thumb_seen = true;
-            match mode {
-                Mode::Locate => {}
-                Mode::DryRun => {
-                    info!("Would delete a thumbnail for {}", path.to_string_lossy());
-                }
-                Mode::Delete => {
-                    info!("Deleting a thumbnail for '{}'", path.to_string_lossy());
+    .map_err(|_| Error::NonAbsolutePath(path.to_path_buf()).into())
+}
 
-                    remove_file(&thumb)
-                        .with_context(|| format!("Failed to delete {}", thumb.to_string_lossy()))?;
-                }
-            }
-            let th = Thumbnail {
-                thumbnail: thumb,
-                file: path.to_path_buf(),
-            };
-            acc_paths.push(th);
-        } else {
-            debug!("  Not found  {:?}", thumb);
-        }
-    }
+/// The file name of the thumbnails for the file at `url`.
+fn thumbnail_file_name(url: &Url) -> String {
+    uri_file_name(url.as_str())
+}
 
-    if !thumb_seen {
-        debug!(
-            "Could not find a thumbnail for '{}'",
-            path.to_string_lossy()
-        );
-    }
+/// The file name of the thumbnails whose `Thumb::URI` is `uri`.
+fn uri_file_name(uri: &str) -> String {
+    format!("{:x}.png", md5::compute(uri.as_bytes()))
+}
 
-    Ok(())
+/// Check if the file at `path` doesn't exist. Unlike with `Path::exists`, other
+/// errors (like permission errors) don't make a file count as missing.
+fn is_missing(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(_) => false,
+        Err(e) => {
+            e.kind() == std::io::ErrorKind::NotFound || e.raw_os_error() == Some(libc::ENOTDIR)
+        }
+    }
 }
 
 fn is_hidden_unix(str: &OsStr) -> bool {
@@ -235,14 +452,13 @@ fn is_hidden_unix(str: &OsStr) -> bool {
     c == '.'
 }
 
-fn find_cache_locations() -> Result<Vec<PathBuf>> {
-    let mut cache = dirs::cache_dir().ok_or_else(|| anyhow!("Could not find cache directory"))?;
-    cache.push("thumbnails/");
-
+fn find_cache_locations(cache: &Path) -> Result<Vec<PathBuf>> {
     // TODO this ignores errors in iterating the subdirs
     let init_locations = [
         cache.join("normal"),
         cache.join("large"),
+        cache.join("x-large"),
+        cache.join("xx-large"),
         cache.join("fail"),
     ];
     let mut locations = Vec::new();
@@ -270,23 +486,31 @@ fn find_cache_locations() -> Result<Vec<PathBuf>> {
 }
 
 fn clean_thumbnail(
-    path: &Path,
+    entry: &CacheEntry,
     force: bool,
+    rename: bool,
     exclude: &GlobSet,
     include: &GlobSet,
-    acc_paths: &mut Vec<Thumbnail>,
+    results: &mut CleanupResults,
+    un: &UnThumbnailer,
 ) -> Result<()> {
+    let path = entry.thumbnail();
     trace!("Processing {:?}", path);
-    let origin = find_uri_for_thumbnail(path)?;
+    let origin = entry
+        .uri()?
+        .ok_or_else(|| anyhow!("failed to find origin path"))?;
 
     let origin_url = Url::parse(&origin).map_err(|s| format_err!("{}", s))?;
     if origin_url.scheme() == "file" {
         let origin_path = origin_url.to_file_path().unwrap();
         let glob_candidate = Candidate::new(&origin_path);
-        if !exclude.is_match_candidate(&glob_candidate)
-            && include.is_match_candidate(&glob_candidate)
-            && !origin_path.exists()
+        if exclude.is_match_candidate(&glob_candidate)
+            || !include.is_match_candidate(&glob_candidate)
         {
+            return Ok(());
+        }
+
+        if is_missing(&origin_path) {
             if !force {
                 if log_enabled!(log::Level::Info) {
                     info!(
@@ -301,14 +525,32 @@ fn clean_thumbnail(
                         origin_path.to_string_lossy()
                     );
                 }
-                remove_file(&path)
-                    .with_context(|| format!("failed to delete file {}", path.to_string_lossy()))?;
             }
             let th = Thumbnail {
                 thumbnail: path.to_path_buf(),
                 file: origin_path,
+                app: entry.app().map(String::from),
             };
-            acc_paths.push(th);
+            if force {
+                if let Err(e) = un.remove_thumbnail(&th) {
+                    debug!("Failed to delete {}: {}", path.to_string_lossy(), e);
+                    results.failed.push(FailedThumbnail::new(th, &e));
+                    return Ok(());
+                }
+            }
+            results.thumbnails.push(th);
+        } else {
+            let expected = uri_file_name(&origin);
+            if path.file_name().is_some_and(|n| n != expected.as_str()) {
+                let th = Thumbnail {
+                    thumbnail: path.to_path_buf(),
+                    file: origin_path,
+                    app: entry.app().map(String::from),
+                };
+                results
+                    .mismatched
+                    .push(rename_mismatched(th, &expected, rename, un)?);
+            }
         }
     } else {
         trace!(
@@ -320,30 +562,113 @@ fn clean_thumbnail(
     Ok(())
 }
 
-fn find_uri_for_thumbnail(path: &Path) -> Result<String> {
-    let reader = BufReader::new(File::open(path)?);
-    for chunk in Decoder::new(reader)?.into_chunks() {
-        match chunk {
-            Ok(c) => match c {
-                Chunk::CompressedText(text) => {
-                    if text.key == "Thumb::URI" {
-                        return Ok(text.val);
-                    }
-                }
-                Chunk::Text(text) => {
-                    if text.key == "Thumb::URI" {
-                        return Ok(text.val);
-                    }
-                }
-                _ => (),
-            },
+/// Rename the thumbnail `thumb` to `name`, the name matching its `Thumb::URI`, if
+/// `rename` is set. If a thumbnail with that name already exists, `thumb` is a
+/// useless duplicate and is deleted instead.
+fn rename_mismatched(
+    thumb: Thumbnail,
+    name: &str,
+    rename: bool,
+    un: &UnThumbnailer,
+) -> Result<MovedThumbnail> {
+    let new_thumb = thumb.thumbnail.with_file_name(name);
+    if !rename {
+        info!(
+            "Would rename a misnamed thumbnail for {}",
+            thumb.file.to_string_lossy()
+        );
+    } else if new_thumb.exists() {
+        info!(
+            "Deleting a duplicate thumbnail for '{}'",
+            thumb.file.to_string_lossy()
+        );
+        un.remove_thumbnail(&thumb).with_context(|| {
+            format!(
+                "failed to delete file {}",
+                thumb.thumbnail.to_string_lossy()
+            )
+        })?;
+    } else {
+        info!(
+            "Renaming a misnamed thumbnail for '{}'",
+            thumb.file.to_string_lossy()
+        );
+        let renamed = std::fs::rename(&thumb.thumbnail, &new_thumb);
+        un.record(
+            JournalAction::Rename,
+            &thumb.thumbnail,
+            Some(&thumb.file),
+            Some(&new_thumb),
+            &renamed,
+        );
+        renamed.with_context(|| {
+            format!(
+                "failed to rename file {}",
+                thumb.thumbnail.to_string_lossy()
+            )
+        })?;
+    }
+
+    let file = thumb.file.clone();
+    let app = thumb.app.clone();
+    Ok(MovedThumbnail {
+        from: thumb,
+        to: Thumbnail {
+            thumbnail: new_thumb,
+            file,
+            app,
+        },
+    })
+}
+
+/// Metadata stored in the text chunks of a thumbnail.
+#[derive(Debug, Default, Clone)]
+struct ThumbnailMetadata {
+    /// `Thumb::URI`: the URI of the original file
+    uri: Option<String>,
+    /// `Thumb::MTime`: the modification time of the original file, in seconds
+    mtime: Option<u64>,
+}
+
+fn read_metadata<R: Read>(reader: R) -> Result<ThumbnailMetadata> {
+    let mut meta = ThumbnailMetadata::default();
+    for chunk in Decoder::new(reader).map_err(png::invalid)?.into_chunks() {
+        let (key, val) = match chunk {
+            Ok(Chunk::CompressedText(text)) => (text.key, text.val),
+            Ok(Chunk::Text(text)) => (text.key, text.val),
+            Ok(_) => continue,
             Err(e) => {
                 trace!("ignored error: {}", e);
+                continue;
             }
+        };
+
+        match key.as_str() {
+            "Thumb::URI" => meta.uri = Some(val),
+            "Thumb::MTime" => meta.mtime = val.trim().parse().ok(),
+            _ => {}
+        }
+        if meta.uri.is_some() && meta.mtime.is_some() {
+            break;
         }
     }
 
-    Err(anyhow!("failed to find origin path"))
+    Ok(meta)
+}
+
+/// Format a size in bytes with binary units, e.g. `1.5 MiB`.
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
 }
 
 #[macro_export]
@@ -359,3 +684,113 @@ macro_rules! show {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{thumbnail_path, thumbnailed_file, TempDir};
+    use globset::{Glob, GlobSetBuilder};
+    use std::fs::{copy, remove_file, rename};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn cleanup(un: &UnThumbnailer, force: bool, rename: bool) -> CleanupResults {
+        let all = GlobSetBuilder::new()
+            .add(Glob::new("**").unwrap())
+            .build()
+            .unwrap();
+        un.cleanup(force, rename, &GlobSet::empty(), &all).unwrap()
+    }
+
+    #[test]
+    fn rename_mismatched_thumbnails() {
+        let dir = TempDir::new("cleanup-mismatched");
+        let cache = dir.path().join("cache");
+        let file = dir.path().join("file");
+        let thumb = thumbnailed_file(&cache, &file);
+        let misnamed = thumb.with_file_name("misnamed.png");
+        rename(&thumb, &misnamed).unwrap();
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        // only reported without both force and rename
+        for (force, rename) in [(false, true), (true, false)] {
+            let results = cleanup(&un, force, rename);
+            assert_eq!(results.mismatched.len(), 1);
+            assert_eq!(results.mismatched[0].to.thumbnail, thumb);
+            assert!(misnamed.exists() && !thumb.exists());
+        }
+
+        let results = cleanup(&un, true, true);
+        assert_eq!(results.mismatched.len(), 1);
+        assert!(!misnamed.exists() && thumb.exists());
+        assert!(cleanup(&un, true, true).mismatched.is_empty());
+    }
+
+    #[test]
+    fn delete_mismatched_duplicates() {
+        let dir = TempDir::new("cleanup-duplicate");
+        let cache = dir.path().join("cache");
+        let file = dir.path().join("file");
+        let thumb = thumbnailed_file(&cache, &file);
+        let duplicate = thumb.with_file_name("duplicate.png");
+        copy(&thumb, &duplicate).unwrap();
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        assert_eq!(cleanup(&un, true, true).mismatched.len(), 1);
+        assert!(!duplicate.exists() && thumb.exists());
+    }
+
+    #[test]
+    fn cleanup_thumbnails_of_missing_files() {
+        let dir = TempDir::new("cleanup-missing");
+        let cache = dir.path().join("cache");
+        let (kept, missing) = (dir.path().join("kept"), dir.path().join("missing"));
+        thumbnailed_file(&cache, &kept);
+        thumbnailed_file(&cache, &missing);
+        remove_file(&missing).unwrap();
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        assert_eq!(cleanup(&un, false, false).thumbnails.len(), 1);
+        assert!(thumbnail_path(&cache, &missing).exists());
+        let results = cleanup(&un, true, false);
+        assert_eq!(results.thumbnails[0].file, missing);
+        assert!(!thumbnail_path(&cache, &missing).exists());
+        assert!(thumbnail_path(&cache, &kept).exists());
+    }
+
+    #[test]
+    fn interrupt_and_resume_cleanup() {
+        let dir = TempDir::new("cleanup-interrupted");
+        let cache = dir.path().join("cache");
+        let files: Vec<_> = ["a", "b", "c"].iter().map(|n| dir.path().join(n)).collect();
+        for file in &files {
+            thumbnailed_file(&cache, file);
+            remove_file(file).unwrap();
+        }
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        // stop after the first thumbnail
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        un.on_progress(move |p| stopped.store(p.done == 1, Ordering::SeqCst));
+        un.cancel_when(move || stop.load(Ordering::SeqCst));
+
+        let results = cleanup(&un, true, false);
+        assert!(results.interrupted);
+        assert_eq!(results.thumbnails.len(), 1);
+        let last = results.resume_after.unwrap();
+        assert_eq!(last, results.thumbnails[0].thumbnail);
+        assert!(!last.exists());
+
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        let all = GlobSetBuilder::new()
+            .add(Glob::new("**").unwrap())
+            .build()
+            .unwrap();
+        let results = un
+            .cleanup_after(true, false, &GlobSet::empty(), &all, Some(&last))
+            .unwrap();
+        assert!(!results.interrupted);
+        assert_eq!(results.thumbnails.len(), 2);
+        assert!(files.iter().all(|f| !thumbnail_path(&cache, f).exists()));
+    }
+}
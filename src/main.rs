@@ -3,12 +3,20 @@ use clap::{CommandFactory, FromArgMatches};
 use env_logger::{Builder, Env};
 use globset::{Glob, GlobSet, GlobSetBuilder};
 use log::*;
-use std::io::Write;
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::SystemTime;
-use thumbs::cli::{Command, ProgramOptions};
-use thumbs::{show, Thumbnail, UnThumbnailer};
+use thumbs::cli::{
+    level_filter, Command, ImageProtocol, OutputFormat, ProgramOptions, ProgressMode, ThumbnailSize,
+};
+use thumbs::config::Config;
+use thumbs::dbus::{notify, ThumbnailService};
+use thumbs::progress::ProgressReporter;
+use thumbs::{
+    human_size, preview, prompt, show, CleanupResults, ExportTarget, FailedThumbnail,
+    MovedThumbnail, Thumbnail, UnThumbnailer, User, WatchEvent,
+};
 
 const LOG_ENV_VAR: &str = "THUMBS_LOG";
 
@@ -18,7 +26,16 @@ fn main() {
         Ok(true) => exit(0),
         // Found nothing to do
         Ok(false) => exit(125),
+        // Some thumbnails couldn't be deleted, already reported (clap uses 2
+        // for usage errors)
+        Err(e) if e.is::<PartialFailure>() => exit(3),
+        // Stopped by SIGINT, partial results already reported
+        Err(e) if e.is::<Interrupted>() => exit(130),
         Err(e) => {
+            if NOTIFY.load(Ordering::SeqCst) {
+                let causes: Vec<_> = e.chain().skip(1).map(|c| c.to_string()).collect();
+                send_notification(&format!("Error: {}", e), &causes.join("\n"), true);
+            }
             let causes = e.chain().skip(1);
             if causes.len() != 0 {
                 if log_enabled!(Level::Info) {
@@ -37,6 +54,164 @@ fn main() {
     }
 }
 
+/// Only some of the thumbnails could be deleted.
+#[derive(Debug)]
+struct PartialFailure;
+
+impl std::fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "some thumbnails could not be deleted")
+    }
+}
+
+impl std::error::Error for PartialFailure {}
+
+/// The operation was stopped early by SIGINT.
+#[derive(Debug)]
+struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Set by the first SIGINT received while running `interruptible`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: std::os::raw::c_int) {
+    // a second Ctrl-C doesn't wait for the current thumbnail
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Run `f`, with SIGINT only setting `INTERRUPTED` so that `f` can stop after
+/// the current thumbnail and return what it did so far.
+fn interruptible<T, F: FnOnce() -> T>(f: F) -> T {
+    // SAFETY: the handler only touches an atomic, and exits
+    unsafe { libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t) };
+    let result = f();
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+
+    result
+}
+
+/// Set with `--notify`, to show the outcome of the command as a desktop
+/// notification.
+static NOTIFY: AtomicBool = AtomicBool::new(false);
+
+/// Show the outcome of deleting thumbnails as a desktop notification, with
+/// `--notify`.
+fn notify_deleted(deleted: usize, failed: &[FailedThumbnail], interrupted: bool) {
+    if !NOTIFY.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let summary = if !failed.is_empty() {
+        format!(
+            "Failed to delete {} of {} thumbnail(s)",
+            failed.len(),
+            failed.len() + deleted
+        )
+    } else if interrupted {
+        format!("Interrupted after deleting {} thumbnail(s)", deleted)
+    } else if deleted == 0 {
+        "Found no thumbnails to delete".to_string()
+    } else {
+        format!("Deleted {} thumbnail(s)", deleted)
+    };
+    let mut body: Vec<_> = failed
+        .iter()
+        .take(5)
+        .map(|f| format!("{}: {}", f.thumbnail.file.to_string_lossy(), f.reason))
+        .collect();
+    if failed.len() > 5 {
+        body.push(format!("and {} more", failed.len() - 5));
+    }
+    send_notification(&summary, &body.join("\n"), !failed.is_empty());
+}
+
+/// Failing to notify is only worth a warning.
+fn send_notification(summary: &str, body: &str, critical: bool) {
+    if let Err(e) = notify(summary, body, critical) {
+        warn!(
+            "Failed to send a desktop notification: {:#}",
+            anyhow::Error::from(e)
+        );
+    }
+}
+
+/// Tells the thumbnail service on the session bus about the changes made to the
+/// cache, with `--dbus`. Failing to do so is only worth a warning.
+struct CacheNotifier {
+    service: Option<ThumbnailService>,
+}
+
+impl CacheNotifier {
+    fn new(enabled: bool) -> Result<Self> {
+        let service = if enabled {
+            match ThumbnailService::connect() {
+                Ok(s) => Some(s),
+                Err(e) if cfg!(feature = "dbus") => {
+                    warn!("{}; the thumbnail service won't be notified.", e);
+                    if let Some(cause) = std::error::Error::source(&e) {
+                        info!("cause: {}", cause);
+                    }
+                    None
+                }
+                // built without D-Bus support
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            None
+        };
+
+        Ok(CacheNotifier { service })
+    }
+
+    fn deleted(&self, thumbnails: &[Thumbnail]) {
+        if thumbnails.is_empty() {
+            return;
+        }
+        if let Some(service) = &self.service {
+            let files: Vec<_> = thumbnails.iter().map(|t| t.file.clone()).collect();
+            warn_failed_notify(service.delete(&files));
+        }
+    }
+
+    fn moved(&self, moved: &[MovedThumbnail], copied: bool) {
+        if moved.is_empty() {
+            return;
+        }
+        if let Some(service) = &self.service {
+            let files: Vec<_> = moved
+                .iter()
+                .map(|m| (m.from.file.clone(), m.to.file.clone()))
+                .collect();
+            warn_failed_notify(if copied {
+                service.copy_files(&files)
+            } else {
+                service.move_files(&files)
+            });
+        }
+    }
+}
+
+/// The value of a flag enabled with `--flag` or disabled with `--no-flag` on the
+/// command line, or else in the configuration file.
+fn flag(enabled: bool, disabled: bool, config: bool) -> bool {
+    enabled || (config && !disabled)
+}
+
+fn warn_failed_notify<E: Into<anyhow::Error>>(result: Result<(), E>) {
+    if let Err(e) = result {
+        warn!("Failed to notify the thumbnail service: {:#}", e.into());
+    }
+}
+
 fn run() -> Result<bool> {
     let args_matches = ProgramOptions::command().get_matches();
     let args = ProgramOptions::from_arg_matches(&args_matches)?;
@@ -55,16 +230,23 @@ fn run() -> Result<bool> {
         return Ok(true);
     }
 
+    // the config file can change the default log level, so errors loading it
+    // can only be reported once logging is set up
+    let config = Config::load(args.config.as_deref());
+    let default_level = 2i8.saturating_add(config.as_ref().map_or(0, |c| c.verbose));
+
     let mut b = Builder::default();
     b.format_timestamp(None);
-    b.filter_level(LevelFilter::Warn); // default filter lever
+    b.filter_level(level_filter(default_level)); // default filter level
     b.parse_env(Env::from(LOG_ENV_VAR)); // override with env
                                          // override with CLI option
-    if let Some(level) = args.log_level_with_default(2) {
+    if let Some(level) = args.log_level_with_default(default_level) {
         b.filter_level(level);
     };
     b.try_init()?;
 
+    let config = config?;
+
     let cmd = if let Some(cmd) = &args.cmd {
         cmd
     } else {
@@ -72,13 +254,112 @@ fn run() -> Result<bool> {
         std::process::exit(1);
     };
 
-    let un = thumbs::UnThumbnailer::new(args.recursive, args.all)?;
+    let recursive = flag(args.recursive, args.no_recursive, config.recursive);
+    let all = flag(args.all, args.no_all, config.all);
+    let respect_ignore = flag(
+        args.respect_ignore,
+        args.no_respect_ignore,
+        config.respect_ignore,
+    );
+    let output = args.output.or(config.output).unwrap_or(OutputFormat::Human);
+    let image_protocol = args
+        .image_protocol
+        .or(config.image_protocol)
+        .unwrap_or_else(preview::detect_protocol);
+    let review_preview = if flag(args.preview, args.no_preview, config.preview) {
+        Some(image_protocol)
+    } else {
+        None
+    };
+    let cache_dir = args.cache_dir.as_ref().or(config.cache_dir.as_ref());
+    let mut un = match cache_dir {
+        Some(dir) => UnThumbnailer::with_cache_dir(recursive, all, respect_ignore, dir)?,
+        None => UnThumbnailer::new(recursive, all, respect_ignore)?,
+    };
+    let command_line = std::env::args_os()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    // only the commands that change the cache have something to record
+    if matches!(
+        cmd,
+        Command::Cleanup { .. }
+            | Command::Delete { .. }
+            | Command::MigrateLegacy { .. }
+            | Command::Undo
+            | Command::ServeDbus
+            | Command::Fsck { .. }
+            | Command::Restore { .. }
+            | Command::Mv { .. }
+            | Command::CpThumbs { .. }
+            | Command::Relocate { .. }
+            | Command::Watch { .. }
+    ) {
+        if let Err(e) = un.enable_journal(&command_line) {
+            warn!("{}; changes won't be recorded in the journal.", e);
+            if let Some(cause) = std::error::Error::source(&e) {
+                info!("cause: {}", cause);
+            }
+        }
+    }
+    if flag(args.quarantine, args.no_quarantine, config.quarantine) {
+        un.enable_quarantine()?;
+    }
+    // an explicit cache directory replaces the caches of sandboxed apps too
+    if cache_dir.is_none() || !args.app.is_empty() {
+        if let Some(home) = dirs::home_dir() {
+            un.add_app_caches(&home, &args.app)?;
+        }
+    }
+    if matches!(
+        cmd,
+        Command::Cleanup { .. } | Command::Delete { .. } | Command::Stats
+    ) {
+        // the progress bar would get in the way of verbose messages
+        let reporter = match args.progress.or(config.progress) {
+            Some(ProgressMode::Json) => Some(ProgressReporter::json()),
+            Some(ProgressMode::Never) => None,
+            _ if atty::is(atty::Stream::Stderr) && !log_enabled!(Level::Info) => {
+                Some(ProgressReporter::bar())
+            }
+            _ => None,
+        };
+        if let Some(reporter) = reporter {
+            un.on_progress(move |p| reporter.report(p));
+        }
+    }
+    un.cancel_when(|| INTERRUPTED.load(Ordering::SeqCst));
+    let notifier = CacheNotifier::new(flag(args.dbus, args.no_dbus, config.dbus))?;
+    if flag(args.notify, args.no_notify, config.notify) {
+        // callers like file manager extensions can't know how thumbs was built
+        if cfg!(feature = "dbus") {
+            NOTIFY.store(true, Ordering::SeqCst);
+        } else {
+            warn!("thumbs was built without D-Bus support; no desktop notification will be shown.");
+        }
+    }
     match cmd {
-        Command::Cleanup { force, glob } => {
+        Command::Cleanup {
+            force,
+            rename_mismatched,
+            all_users,
+            resume,
+            home,
+            glob,
+            profile,
+        } => {
+            let force = *force;
+            let mut globs = config.glob.clone();
+            if let Some(name) = profile {
+                let profile = config.profile(name)?;
+                globs.extend_from_slice(&profile.glob);
+            }
+            globs.extend_from_slice(glob);
+
             let mut builder_exclude = GlobSetBuilder::new();
             let mut builder_include = GlobSetBuilder::new();
             let mut include_all = true;
-            for g in glob {
+            for g in &globs {
                 if g.starts_with('!') {
                     builder_exclude.add(Glob::new(g.strip_prefix('!').unwrap())?);
                 } else {
@@ -92,59 +373,624 @@ fn run() -> Result<bool> {
             let set_exclude = builder_exclude.build()?;
             let set_include = builder_include.build()?;
 
-            do_cleanup(&un, *force, &set_exclude, &set_include)
+            if *all_users || !home.is_empty() {
+                let users = if *all_users {
+                    User::all()?
+                } else {
+                    home.iter()
+                        .map(|h| User::from_home(h))
+                        .collect::<Result<Vec<_>, thumbs::Error>>()?
+                };
+                return do_cleanup_users(
+                    &un,
+                    &users,
+                    force,
+                    *rename_mismatched,
+                    &set_exclude,
+                    &set_include,
+                    output,
+                );
+            }
+
+            let after = if *resume {
+                let after = un.checkpoint()?;
+                if after.is_none() {
+                    warn!("Found no interrupted cleanup to resume, starting from the beginning.");
+                }
+                after
+            } else {
+                None
+            };
+            let results = interruptible(|| {
+                un.cleanup_after(
+                    force,
+                    *rename_mismatched,
+                    &set_exclude,
+                    &set_include,
+                    after.as_deref(),
+                )
+            })?;
+
+            do_cleanup(
+                &un,
+                &notifier,
+                results,
+                force,
+                *rename_mismatched,
+                output,
+                review_preview,
+            )
         }
         Command::Delete {
             force,
             files,
             last_accessed,
-        } => do_delete(&un, files, *force, *last_accessed),
+        } => do_delete(
+            &un,
+            &notifier,
+            files,
+            *force,
+            *last_accessed,
+            output,
+            review_preview,
+        ),
         Command::Locate { file } => {
             let thumbs = un.locate(file)?;
 
-            for p in &thumbs {
-                show!("{}", p.thumbnail.to_string_lossy());
+            match output {
+                OutputFormat::Human => {
+                    for p in &thumbs {
+                        match &p.app {
+                            Some(app) => show!("{} ({})", p.thumbnail.to_string_lossy(), app),
+                            None => show!("{}", p.thumbnail.to_string_lossy()),
+                        }
+                    }
+                }
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&thumbs)?),
             }
 
             Ok(!thumbs.is_empty())
         }
+        Command::Export {
+            files,
+            to,
+            tar,
+            strip,
+            force,
+        } => {
+            let target = match (to, tar) {
+                (_, Some(archive)) => ExportTarget::Tar(archive),
+                (Some(dir), None) => ExportTarget::Dir(dir),
+                (None, None) => return Err(anyhow!("should never happen: no export target")),
+            };
+            let exported = un.export(files, target, *strip, *force)?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&exported)?);
+            } else {
+                show!("Exported {} thumbnail(s).", exported.len());
+            }
+
+            Ok(!exported.is_empty())
+        }
+        Command::MigrateLegacy { force } => {
+            let home = dirs::home_dir().ok_or_else(|| anyhow!("Could not find home directory"))?;
+            let results = un.migrate_legacy(&home, !force)?;
+            if output == OutputFormat::Json {
+                let report = serde_json::json!({ "migrated": force, "results": results });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                if results.invalid != 0 {
+                    info!(
+                        "Skipped {} invalid or outdated thumbnail(s).",
+                        results.invalid
+                    );
+                }
+                if results.existing != 0 {
+                    info!(
+                        "Skipped {} thumbnail(s) already in the cache.",
+                        results.existing
+                    );
+                }
+                if results.migrated.is_empty() && results.invalid == 0 && results.existing == 0 {
+                    warn!("Found no legacy thumbnails to migrate.");
+                } else if *force {
+                    show!("Migrated {} thumbnail(s).", results.migrated.len());
+                } else {
+                    show!(
+                        "Found {} thumbnail(s) to migrate. Use '-v' for details, or '-f/--force' to migrate them and remove the legacy cache.",
+                        results.migrated.len()
+                    );
+                }
+            }
+
+            Ok(!results.migrated.is_empty())
+        }
+        Command::Undo => match un.undo()? {
+            Some(results) => {
+                if output == OutputFormat::Json {
+                    println!("{}", serde_json::to_string_pretty(&results)?);
+                } else {
+                    if results.existing != 0 {
+                        info!(
+                            "Skipped {} thumbnail(s) that were regenerated since.",
+                            results.existing
+                        );
+                    }
+                    show!("Restored {} thumbnail(s).", results.restored.len());
+                    for f in &results.failed {
+                        warn!(
+                            "Failed to restore {}: {}",
+                            f.thumbnail.thumbnail.to_string_lossy(),
+                            f.reason
+                        );
+                        info!("cause: {}", f.error);
+                    }
+                }
+                if !results.failed.is_empty() {
+                    if results.restored.is_empty() {
+                        return Err(anyhow!(
+                            "Failed to restore {} thumbnail(s), rerun 'undo' to try again",
+                            results.failed.len()
+                        ));
+                    }
+                    if output == OutputFormat::Human {
+                        warn!(
+                            "Failed to restore {} of {} thumbnail(s), rerun 'undo' to try again.",
+                            results.failed.len(),
+                            results.failed.len() + results.restored.len()
+                        );
+                    }
+                    return Err(PartialFailure.into());
+                }
+
+                Ok(!results.restored.is_empty())
+            }
+            None => {
+                if output == OutputFormat::Json {
+                    println!("null");
+                } else {
+                    warn!("Found nothing to undo.");
+                }
+
+                Ok(false)
+            }
+        },
+        Command::History { since, until, path } => {
+            let path = path
+                .as_ref()
+                .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()));
+            let entries = un.history(*since, *until, path.as_deref())?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                for e in &entries {
+                    let mut line = format!(
+                        "{} {:<10} {}",
+                        e.time,
+                        e.action.to_string(),
+                        e.thumbnail.to_string_lossy()
+                    );
+                    if let Some(dest) = &e.destination {
+                        line.push_str(&format!(" -> {}", dest.to_string_lossy()));
+                    }
+                    if let Some(file) = &e.file {
+                        line.push_str(&format!(" ('{}')", file.to_string_lossy()));
+                    }
+                    if e.result != "ok" {
+                        line.push_str(&format!(": failed: {}", e.result));
+                    }
+                    show!("{}", line);
+                }
+            }
+
+            Ok(!entries.is_empty())
+        }
+        Command::Show { file, width } => {
+            let thumb = un
+                .best_thumbnail(file)?
+                .ok_or_else(|| anyhow!("Found no thumbnail for {}", file.to_string_lossy()))?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&thumb)?);
+            } else {
+                let data = std::fs::read(&thumb.thumbnail)?;
+                let (columns, rows) = preview::terminal_size().unwrap_or((80, 24));
+                let columns = width.map_or(columns, |w| w.min(columns));
+                let out = std::io::stdout();
+                preview::show_image(
+                    &mut out.lock(),
+                    &data,
+                    image_protocol,
+                    columns,
+                    rows.saturating_sub(1).max(1),
+                )?;
+            }
+
+            Ok(true)
+        }
+        Command::Generate { files, size } => {
+            let flavor = match size {
+                ThumbnailSize::Normal => "normal",
+                ThumbnailSize::Large => "large",
+                ThumbnailSize::XLarge => "x-large",
+                ThumbnailSize::XxLarge => "xx-large",
+            };
+            let results = ThumbnailService::connect()?.generate(files, flavor)?;
+            let generated = results.generated.len();
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for f in &results.failed {
+                    warn!(
+                        "Failed to generate a thumbnail for {}: {}",
+                        f.file.to_string_lossy(),
+                        f.error
+                    );
+                }
+                if generated != 0 {
+                    show!("Generated {} thumbnail(s).", generated);
+                }
+            }
+            if !results.failed.is_empty() {
+                if generated == 0 {
+                    return Err(anyhow!(
+                        "Failed to generate {} thumbnail(s)",
+                        results.failed.len()
+                    ));
+                }
+                return Err(PartialFailure.into());
+            }
+
+            Ok(generated != 0)
+        }
+        Command::ServeDbus => {
+            // file managers move and copy whole directories
+            un.recursive = true;
+            un.serve_dbus()?;
+
+            Ok(true)
+        }
+        Command::Stats => {
+            let stats = un.stats()?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                for s in stats.iter().filter(|s| s.thumbnails != 0) {
+                    let app = s.app.as_ref().map(|a| format!(" ({})", a));
+                    show!(
+                        "{}{}: {} thumbnail(s), {}",
+                        s.dir.to_string_lossy(),
+                        app.unwrap_or_default(),
+                        s.thumbnails,
+                        human_size(s.size)
+                    );
+                }
+                show!(
+                    "Total: {} thumbnail(s), {}",
+                    stats.iter().map(|s| s.thumbnails).sum::<u64>(),
+                    human_size(stats.iter().map(|s| s.size).sum())
+                );
+            }
+
+            Ok(stats.iter().any(|s| s.thumbnails != 0))
+        }
+        Command::Fsck { repair } => {
+            let issues = un.fsck(*repair)?;
+            if output == OutputFormat::Json {
+                let report = serde_json::json!({ "repair": repair, "issues": issues });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if issues.is_empty() {
+                show!("Found no problems.");
+            } else {
+                for issue in &issues {
+                    show!("{}: {}", issue.path.to_string_lossy(), issue.problem);
+                }
+                let repaired = issues.iter().filter(|i| i.repaired).count();
+                if *repair {
+                    show!("Repaired {} of {} problem(s).", repaired, issues.len());
+                } else {
+                    show!(
+                        "Found {} problem(s). Use '--repair' to fix them.",
+                        issues.len()
+                    );
+                }
+            }
+
+            Ok(!issues.is_empty())
+        }
+        Command::Montage {
+            dir,
+            image,
+            columns,
+        } => {
+            let thumbs = un.montage(dir, image, *columns)?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&thumbs)?);
+            } else if thumbs.is_empty() {
+                show!("Found no thumbnails to tile.");
+            } else {
+                show!("Created a montage of {} thumbnail(s).", thumbs.len());
+            }
+
+            Ok(!thumbs.is_empty())
+        }
+        Command::Backup { archive } => {
+            let thumbs = un.backup(archive)?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&thumbs)?);
+            } else {
+                show!("Archived {} thumbnail(s).", thumbs.len());
+            }
+
+            Ok(!thumbs.is_empty())
+        }
+        Command::Restore { archive } => {
+            let results = un.restore(archive)?;
+            if output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                if results.stale != 0 {
+                    warn!(
+                        "Skipped {} thumbnail(s) for files that were deleted or modified.",
+                        results.stale
+                    );
+                }
+                if results.unknown_cache != 0 {
+                    warn!(
+                        "Skipped {} thumbnail(s) of caches that aren't used anymore.",
+                        results.unknown_cache
+                    );
+                }
+                if results.existing != 0 {
+                    info!(
+                        "Skipped {} thumbnail(s) already in the cache.",
+                        results.existing
+                    );
+                }
+                show!("Restored {} thumbnail(s).", results.restored.len());
+            }
+
+            Ok(!results.restored.is_empty())
+        }
+        Command::Mv { source, dest } => {
+            let moved = un.move_file(source, dest)?;
+            notifier.moved(&moved, false);
+            print_moved(&moved, true, output)
+        }
+        Command::CpThumbs { source, dest } => {
+            let copied = un.copy_thumbnails(source, dest)?;
+            notifier.moved(&copied, true);
+            if output == OutputFormat::Json {
+                let report = serde_json::json!({ "copied": true, "thumbnails": copied });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if copied.is_empty() {
+                warn!("Found no thumbnails to copy. Rerun with '-vv' for detailed information.")
+            } else {
+                show!("Copied {} thumbnail(s).", copied.len());
+            }
+
+            Ok(!copied.is_empty())
+        }
+        Command::Relocate { force, from, to } => {
+            let moved = un.relocate(from, to, !force)?;
+            if *force {
+                notifier.moved(&moved, false);
+            }
+            print_moved(&moved, *force, output)
+        }
+        Command::Watch { force, dirs } => {
+            un.watch(dirs, !force, |event| {
+                if *force {
+                    match event {
+                        WatchEvent::Deleted(t) => notifier.deleted(std::slice::from_ref(t)),
+                        WatchEvent::Moved(m) => notifier.moved(std::slice::from_ref(m), false),
+                    }
+                }
+                match (output, event) {
+                    (OutputFormat::Human, WatchEvent::Deleted(t)) => {
+                        if *force {
+                            show!("Deleted a thumbnail for {}", t.file.to_string_lossy());
+                        } else {
+                            show!("Would delete a thumbnail for {}", t.file.to_string_lossy());
+                        }
+                    }
+                    (OutputFormat::Human, WatchEvent::Moved(m)) => {
+                        if *force {
+                            show!(
+                                "Moved a thumbnail for {} to {}",
+                                m.from.file.to_string_lossy(),
+                                m.to.file.to_string_lossy()
+                            );
+                        } else {
+                            show!(
+                                "Would move a thumbnail for {} to {}",
+                                m.from.file.to_string_lossy(),
+                                m.to.file.to_string_lossy()
+                            );
+                        }
+                    }
+                    (OutputFormat::Json, WatchEvent::Deleted(t)) => {
+                        let event = serde_json::json!({ "deleted": force, "thumbnail": t });
+                        println!("{}", event);
+                    }
+                    (OutputFormat::Json, WatchEvent::Moved(m)) => {
+                        let event = serde_json::json!({ "moved": force, "thumbnail": m });
+                        println!("{}", event);
+                    }
+                }
+            })?;
+
+            Ok(true)
+        }
     }
 }
 
 fn do_cleanup(
     un: &UnThumbnailer,
+    notifier: &CacheNotifier,
+    results: CleanupResults,
     force: bool,
-    set_exclude: &GlobSet,
-    set_include: &GlobSet,
+    rename: bool,
+    output: OutputFormat,
+    preview: Option<ImageProtocol>,
 ) -> Result<bool> {
-    let thumbs = un.cleanup(force, set_exclude, set_include)?;
+    let thumbs = &results.thumbnails;
     let nb_thumbs = thumbs.len();
-    if nb_thumbs == 0 {
+    let nb_mismatched = results.mismatched.len();
+    if force {
+        notifier.deleted(thumbs);
+        notify_deleted(nb_thumbs, &results.failed, results.interrupted);
+        match &results.resume_after {
+            Some(last) if results.interrupted => un.save_checkpoint(last)?,
+            _ if !results.interrupted => un.clear_checkpoint()?,
+            _ => {}
+        }
+    }
+    if output == OutputFormat::Json {
+        let report = serde_json::json!({
+            "deleted": force,
+            "renamed": force && rename,
+            "interrupted": results.interrupted,
+            "thumbnails": thumbs,
+            "mismatched": results.mismatched,
+            "failed": results.failed,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        check_failed(&results.failed, nb_thumbs, output)?;
+        return check_interrupted(results.interrupted, nb_thumbs != 0 || nb_mismatched != 0);
+    }
+
+    if nb_mismatched != 0 {
+        if force && rename {
+            show!("Renamed {} misnamed thumbnail(s).", nb_mismatched);
+        } else {
+            warn!(
+                "Found {} thumbnail(s) whose name doesn't match their URI. Use '--rename-mismatched' and '-f/--force' to rename them.",
+                nb_mismatched
+            );
+        }
+    }
+    if results.interrupted {
+        if force {
+            show!(
+                "Interrupted after deleting {} thumbnail(s). Use '--resume' to continue.",
+                nb_thumbs
+            );
+        } else {
+            show!(
+                "Interrupted after finding {} thumbnail(s) to delete.",
+                nb_thumbs
+            );
+        }
+    } else if nb_thumbs == 0 && results.failed.is_empty() {
         warn!("Found no thumbnails to cleanup.")
     } else if !force {
         if atty::is(atty::Stream::Stdout) {
-            return user_prompt(&thumbs, || cached_delete(&thumbs));
+            return user_prompt(thumbs, preview, |t| cached_delete(un, notifier, t));
         } else {
             show!(
                 "Found {} thumbnail(s) to delete. Use '-v' for details, or '-f/--force' to delete them.",
                 nb_thumbs
             );
         }
-    } else {
+    } else if nb_thumbs != 0 {
         show!("Deleted {} thumbnail(s).", nb_thumbs);
     }
+    check_failed(&results.failed, nb_thumbs, output)?;
+
+    check_interrupted(results.interrupted, nb_thumbs != 0 || nb_mismatched != 0)
+}
+
+fn do_cleanup_users(
+    un: &UnThumbnailer,
+    users: &[User],
+    force: bool,
+    rename: bool,
+    set_exclude: &GlobSet,
+    set_include: &GlobSet,
+    output: OutputFormat,
+) -> Result<bool> {
+    let all_results =
+        interruptible(|| un.cleanup_users(users, force, rename, set_exclude, set_include))?;
+    let interrupted = all_results.iter().any(|r| r.results.interrupted);
+    let nb_thumbs: usize = all_results.iter().map(|r| r.results.thumbnails.len()).sum();
+    let nb_mismatched: usize = all_results.iter().map(|r| r.results.mismatched.len()).sum();
+    let failed: Vec<FailedThumbnail> = all_results
+        .iter()
+        .flat_map(|r| r.results.failed.iter().cloned())
+        .collect();
+    if force {
+        notify_deleted(nb_thumbs, &failed, interrupted);
+    }
+    if output == OutputFormat::Json {
+        let report = serde_json::json!({
+            "deleted": force,
+            "renamed": force && rename,
+            "users": all_results,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        check_failed(&failed, nb_thumbs, output)?;
+        return check_interrupted(interrupted, nb_thumbs != 0 || nb_mismatched != 0);
+    }
+
+    let (verb, rename_verb) = match (force, force && rename) {
+        (true, true) => ("deleted", "renamed"),
+        (true, false) => ("deleted", "to rename"),
+        (false, _) => ("to delete", "to rename"),
+    };
+    for r in &all_results {
+        show!(
+            "{}: {} thumbnail(s) {}, {} misnamed thumbnail(s) {}",
+            r.user.name,
+            r.results.thumbnails.len(),
+            verb,
+            r.results.mismatched.len(),
+            rename_verb
+        );
+    }
+    if interrupted {
+        show!("Interrupted, the caches of the other users were left alone.");
+    } else if all_results.is_empty() {
+        warn!("Found no user with a thumbnail cache.");
+    } else if force {
+        show!(
+            "Deleted {} thumbnail(s) for {} user(s).",
+            nb_thumbs,
+            all_results.len()
+        );
+    } else if nb_thumbs != 0 {
+        show!(
+            "Found {} thumbnail(s) to delete for {} user(s). Use '-v' for details, or '-f/--force' to delete them.",
+            nb_thumbs,
+            all_results.len()
+        );
+    }
+    check_failed(&failed, nb_thumbs, output)?;
 
-    Ok(nb_thumbs != 0)
+    check_interrupted(interrupted, nb_thumbs != 0 || nb_mismatched != 0)
 }
 
 fn do_delete(
     un: &UnThumbnailer,
+    notifier: &CacheNotifier,
     files: &[PathBuf],
     force: bool,
     last_accessed: Option<SystemTime>,
+    output: OutputFormat,
+    preview: Option<ImageProtocol>,
 ) -> Result<bool> {
-    let results = un.delete(files, !force, last_accessed)?;
+    let results = interruptible(|| un.delete(files, !force, last_accessed))?;
     let thumbnail_count = results.thumbnail_paths.len();
+    if force {
+        notifier.deleted(&results.thumbnail_paths);
+        notify_deleted(thumbnail_count, &results.failed, results.interrupted);
+    }
+
+    if output == OutputFormat::Json {
+        let report = serde_json::json!({ "deleted": force, "results": results });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        check_failed(&results.failed, thumbnail_count, output)?;
+        return check_interrupted(results.interrupted, thumbnail_count != 0);
+    }
 
     if results.ignored_directories != 0 {
         warn!(
@@ -152,12 +998,24 @@ fn do_delete(
             results.ignored_directories
         )
     }
-    if thumbnail_count == 0 {
+    if results.interrupted {
+        if force {
+            show!(
+                "Interrupted after deleting {} thumbnail(s).",
+                thumbnail_count
+            );
+        } else {
+            show!(
+                "Interrupted after finding {} thumbnail(s) to delete.",
+                thumbnail_count
+            );
+        }
+    } else if thumbnail_count == 0 && results.failed.is_empty() {
         warn!("Found no thumbnails. Rerun with '-vv' for detailed information.")
     } else if !force {
         if atty::is(atty::Stream::Stdout) {
-            return user_prompt(&results.thumbnail_paths, || {
-                cached_delete(&results.thumbnail_paths)
+            return user_prompt(&results.thumbnail_paths, preview, |t| {
+                cached_delete(un, notifier, t)
             });
         } else {
             show!(
@@ -165,57 +1023,117 @@ fn do_delete(
                 thumbnail_count
             );
         }
-    } else {
+    } else if thumbnail_count != 0 {
         show!("Deleted {} thumbnail(s).", thumbnail_count);
     }
+    check_failed(&results.failed, thumbnail_count, output)?;
+
+    check_interrupted(results.interrupted, thumbnail_count != 0)
+}
+
+fn print_moved(moved: &[MovedThumbnail], force: bool, output: OutputFormat) -> Result<bool> {
+    if output == OutputFormat::Json {
+        let report = serde_json::json!({ "moved": force, "thumbnails": moved });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else if moved.is_empty() {
+        warn!("Found no thumbnails to move. Rerun with '-vv' for detailed information.")
+    } else if !force {
+        show!(
+            "Found {} thumbnail(s) to move. Use '-v' for details, or '-f/--force' to move them.",
+            moved.len()
+        );
+    } else {
+        show!("Moved {} thumbnail(s).", moved.len());
+    }
 
-    Ok(thumbnail_count != 0)
+    Ok(!moved.is_empty())
 }
 
-fn user_prompt<F>(thumbnails: &[Thumbnail], on_yes: F) -> Result<bool>
+fn user_prompt<F>(
+    thumbnails: &[Thumbnail],
+    preview: Option<ImageProtocol>,
+    on_yes: F,
+) -> Result<bool>
 where
-    F: Fn() -> Result<()>,
+    F: Fn(&[Thumbnail]) -> Result<()>,
 {
-    loop {
-        {
-            let out = std::io::stdout();
-            let mut out = out.lock();
-            write!(
-                out,
-                "Found {} thumbnail(s) to delete.\nDelete them? y(es) / N(o) / d(etails)> ",
-                thumbnails.len()
-            )?;
-            out.flush()?;
-        }
-
-        let mut confirm = String::with_capacity(1);
-        std::io::stdin().read_line(&mut confirm)?;
-        trace!("read user input: {:?}", confirm);
-
-        if confirm.eq_ignore_ascii_case("y\n") {
-            on_yes()?;
-            return Ok(!thumbnails.is_empty());
-        } else if confirm.eq_ignore_ascii_case("d\n") {
-            let out = std::io::stdout();
-            let mut out = out.lock();
-            writeln!(out, "Found thumbnails for:")?;
-            for p in thumbnails {
-                writeln!(out, "{}", p.file.to_string_lossy())?;
-            }
-            out.flush()?;
-        } else {
-            return Ok(!thumbnails.is_empty());
-        }
+    match prompt::confirm_deletion(thumbnails, preview)? {
+        Some(selected) if !selected.is_empty() => on_yes(&selected)?,
+        Some(_) => warn!("No thumbnail selected, nothing was deleted."),
+        None => {}
+    }
+
+    Ok(!thumbnails.is_empty())
+}
+
+fn cached_delete(
+    un: &UnThumbnailer,
+    notifier: &CacheNotifier,
+    thumbnails: &[Thumbnail],
+) -> Result<()> {
+    let failed = un.delete_thumbnails(thumbnails);
+    let deleted = thumbnails.len() - failed.len();
+    let deleted_thumbnails: Vec<_> = thumbnails
+        .iter()
+        .filter(|t| !failed.iter().any(|f| f.thumbnail.thumbnail == t.thumbnail))
+        .cloned()
+        .collect();
+    notifier.deleted(&deleted_thumbnails);
+    notify_deleted(deleted, &failed, false);
+    if deleted != 0 {
+        show!("Deleted {} thumbnail(s).", deleted);
+    }
+
+    check_failed(&failed, deleted, OutputFormat::Human)
+}
+
+/// Fail with `Interrupted` if `interrupted`, once the partial results are
+/// reported, or return `found`.
+fn check_interrupted(interrupted: bool, found: bool) -> Result<bool> {
+    if interrupted {
+        return Err(Interrupted.into());
     }
+
+    Ok(found)
 }
 
-fn cached_delete(thumbnails: &[Thumbnail]) -> Result<()> {
-    for p in thumbnails {
-        std::fs::remove_file(&p.thumbnail)?;
+/// Report the thumbnails that couldn't be deleted, if any, and fail: with a
+/// `PartialFailure` if `deleted` other thumbnails could be deleted.
+fn check_failed(failed: &[FailedThumbnail], deleted: usize, output: OutputFormat) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    if output == OutputFormat::Human {
+        for f in failed {
+            if f.thumbnail.thumbnail.as_os_str().is_empty() {
+                warn!(
+                    "Failed to look for the thumbnails of {}: {}",
+                    f.thumbnail.file.to_string_lossy(),
+                    f.reason
+                );
+            } else {
+                warn!(
+                    "Failed to delete {}: {}",
+                    f.thumbnail.thumbnail.to_string_lossy(),
+                    f.reason
+                );
+            }
+            info!("cause: {}", f.error);
+        }
+    }
+    if deleted == 0 {
+        return Err(anyhow!("Failed to delete {} thumbnail(s)", failed.len()));
+    }
+    if output == OutputFormat::Human {
+        warn!(
+            "Failed to delete {} of {} thumbnail(s).",
+            failed.len(),
+            failed.len() + deleted
+        );
     }
 
-    show!("Deleted {} thumbnail(s).", thumbnails.len());
-    Ok(())
+    Err(PartialFailure.into())
 }
 
 fn print_version(long: bool) {
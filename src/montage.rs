@@ -0,0 +1,310 @@
+use crate::png::Image;
+use crate::{is_hidden_unix, Error, Thumbnail, UnThumbnailer};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use std::fs::read;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Size of the square cell of each thumbnail (the size of normal thumbnails).
+const CELL_SIZE: u32 = 128;
+/// Space around cells, and between a cell and its label.
+const PADDING: u32 = 8;
+/// Height of a label.
+const LABEL_HEIGHT: u32 = GLYPH_HEIGHT + 2;
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Space between characters.
+const GLYPH_SPACING: u32 = 1;
+
+const BACKGROUND: [u8; 4] = [0x30, 0x30, 0x30, 0xff];
+const TEXT_COLOR: [u8; 4] = [0xee, 0xee, 0xee, 0xff];
+
+impl UnThumbnailer {
+    /// Tile the cached thumbnails of the files in the directory at `dir` into a
+    /// single PNG image written to `output`, with `columns` thumbnails per row,
+    /// each labeled with the name of its file. Includes files in subdirectories
+    /// if recursing is enabled.
+    ///
+    /// Files without a thumbnail are ignored. Returns the thumbnails used.
+    pub fn montage(
+        &self,
+        dir: &Path,
+        output: &Path,
+        columns: u32,
+    ) -> Result<Vec<Thumbnail>, Error> {
+        if columns == 0 {
+            return Err(anyhow!("The number of columns must be at least 1").into());
+        }
+
+        let mut walk = WalkDir::new(dir).min_depth(1).sort_by_file_name();
+        if !self.recursive {
+            walk = walk.max_depth(1);
+        }
+        let mut thumbs = Vec::new();
+        for entry in walk
+            .into_iter()
+            .filter_entry(|e| self.hidden || !is_hidden_unix(e.file_name()))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            match self.best_thumbnail(entry.path()) {
+                Ok(Some(t)) => thumbs.push(t),
+                Ok(None) => {}
+                Err(e) => debug!("{} for {}", e, entry.path().to_string_lossy()),
+            }
+        }
+        if thumbs.is_empty() {
+            return Ok(thumbs);
+        }
+
+        let columns = columns.min(thumbs.len() as u32);
+        let rows = (thumbs.len() as u32).div_ceil(columns);
+        let cell_width = CELL_SIZE + PADDING;
+        let cell_height = CELL_SIZE + PADDING + LABEL_HEIGHT + PADDING;
+        let mut sheet = Image::with_color(
+            columns * cell_width + PADDING,
+            rows * cell_height + PADDING,
+            BACKGROUND,
+        );
+
+        for (i, thumb) in thumbs.iter().enumerate() {
+            let x = PADDING + (i as u32 % columns) * cell_width;
+            let y = PADDING + (i as u32 / columns) * cell_height;
+
+            info!("Adding a thumbnail for '{}'", thumb.file.to_string_lossy());
+            match read(&thumb.thumbnail)
+                .map_err(Into::into)
+                .and_then(|data| Image::decode(&data))
+            {
+                Ok(image) => draw_thumbnail(&mut sheet, &image, x, y),
+                Err(e) => warn!("{} for {}", e, thumb.thumbnail.to_string_lossy()),
+            }
+
+            let name = thumb
+                .file
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_default();
+            draw_label(&mut sheet, &name, x, y + CELL_SIZE + PADDING);
+        }
+
+        let data = sheet.encode()?;
+        std::fs::write(output, data)
+            .with_context(|| format!("Failed to write {}", output.to_string_lossy()))?;
+
+        Ok(thumbs)
+    }
+}
+
+/// Draw `image` scaled down to fit in the cell at `(x, y)`.
+fn draw_thumbnail(sheet: &mut Image, image: &Image, x: u32, y: u32) {
+    let largest = image.width.max(image.height);
+    let (width, height) = if largest > CELL_SIZE {
+        (
+            (image.width * CELL_SIZE / largest).max(1),
+            (image.height * CELL_SIZE / largest).max(1),
+        )
+    } else {
+        (image.width, image.height)
+    };
+    let image = image.resize(width, height);
+
+    // centered in the cell
+    let x = x + (CELL_SIZE - width) / 2;
+    let y = y + (CELL_SIZE - height) / 2;
+    for iy in 0..height {
+        for ix in 0..width {
+            let src = image.pixel(ix, iy);
+            let dst = sheet.pixel(x + ix, y + iy);
+            let alpha = src[3] as u32;
+            let mut color = [0xff; 4];
+            for c in 0..3 {
+                color[c] = ((src[c] as u32 * alpha + dst[c] as u32 * (255 - alpha)) / 255) as u8;
+            }
+            sheet.set_pixel(x + ix, y + iy, color);
+        }
+    }
+}
+
+/// Draw `text` centered under the cell at `(x, y)`, truncated to fit.
+fn draw_label(sheet: &mut Image, text: &str, x: u32, y: u32) {
+    let advance = GLYPH_WIDTH + GLYPH_SPACING;
+    let max_chars = ((CELL_SIZE + GLYPH_SPACING) / advance) as usize;
+
+    let mut chars: Vec<char> = text.chars().collect();
+    if chars.len() > max_chars {
+        chars.truncate(max_chars - 2);
+        chars.extend_from_slice(&['.', '.']);
+    }
+
+    let width = chars.len() as u32 * advance - GLYPH_SPACING;
+    let mut cx = x + (CELL_SIZE - width) / 2;
+    for c in chars {
+        draw_glyph(sheet, c, cx, y + 1);
+        cx += advance;
+    }
+}
+
+fn draw_glyph(sheet: &mut Image, c: char, x: u32, y: u32) {
+    let index = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+
+    for (col, bits) in FONT[index].iter().enumerate() {
+        for row in 0..GLYPH_HEIGHT {
+            if bits & (1 << row) != 0 {
+                sheet.set_pixel(x + col as u32, y + row, TEXT_COLOR);
+            }
+        }
+    }
+}
+
+/// 5x7 font for printable ASCII characters, one byte per column (top row in the
+/// lowest bit).
+const FONT: [[u8; GLYPH_WIDTH as usize]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '''
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+    [0x14, 0x08, 0x3e, 0x08, 0x14], // '*'
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // 'F'
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // 'G'
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // 'M'
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\'
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x08, 0x04, 0x08, 0x10, 0x08], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{thumbnailed_file, TempDir};
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn tile_thumbnails() {
+        let dir = TempDir::new("montage");
+        let cache = dir.path().join("cache");
+        let files = dir.path().join("files");
+        create_dir_all(files.join("sub")).unwrap();
+        for name in ["a", "b", "c", "sub/d"] {
+            thumbnailed_file(&cache, &files.join(name));
+        }
+        write(files.join("no-thumbnail"), b"").unwrap();
+        let output = dir.path().join("montage.png");
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        let thumbs = un.montage(&files, &output, 2).unwrap();
+        let names: Vec<_> = thumbs.iter().map(|t| t.file.clone()).collect();
+        assert_eq!(names, [files.join("a"), files.join("b"), files.join("c")]);
+
+        // 2 columns and 2 rows of cells
+        let sheet = Image::decode(&read(&output).unwrap()).unwrap();
+        let cell_height = CELL_SIZE + PADDING + LABEL_HEIGHT + PADDING;
+        assert_eq!(sheet.width, 2 * (CELL_SIZE + PADDING) + PADDING);
+        assert_eq!(sheet.height, 2 * cell_height + PADDING);
+        assert_eq!(sheet.pixel(0, 0), BACKGROUND);
+        // the black 8x8 thumbnail of the first file, centered in its cell
+        let center = PADDING + CELL_SIZE / 2;
+        assert_eq!(sheet.pixel(center, center), [0, 0, 0, 255]);
+
+        let un = UnThumbnailer::with_cache_dir(true, false, false, &cache).unwrap();
+        assert_eq!(un.montage(&files, &output, 2).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn reject_zero_columns() {
+        let dir = TempDir::new("montage-columns");
+        let un = UnThumbnailer::with_cache_dir(false, false, false, dir.path()).unwrap();
+
+        assert!(un
+            .montage(dir.path(), &dir.path().join("out.png"), 0)
+            .is_err());
+    }
+}
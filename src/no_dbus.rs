@@ -0,0 +1,50 @@
+//! Stands in for the D-Bus support of thumbs when it is built without the
+//! `dbus` feature: connecting to the session bus always fails, with an error
+//! saying so.
+
+use crate::{Error, GenerateResults, UnThumbnailer};
+use anyhow::anyhow;
+use std::path::PathBuf;
+
+const NO_DBUS: &str = "thumbs was built without D-Bus support";
+
+/// A connection to the thumbnail services on the session bus, which can't be
+/// made.
+#[derive(Debug)]
+pub enum ThumbnailService {}
+
+impl ThumbnailService {
+    pub fn connect() -> Result<Self, Error> {
+        Err(anyhow!(NO_DBUS).into())
+    }
+
+    pub fn delete(&self, _: &[PathBuf]) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn move_files(&self, _: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn copy_files(&self, _: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn cleanup(&self, _: &[PathBuf], _: u32) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn generate(&self, _: &[PathBuf], _: &str) -> Result<GenerateResults, Error> {
+        match *self {}
+    }
+}
+
+pub fn notify(_: &str, _: &str, _: bool) -> Result<(), Error> {
+    Err(anyhow!(NO_DBUS).into())
+}
+
+impl UnThumbnailer {
+    pub fn serve_dbus(self) -> Result<(), Error> {
+        Err(anyhow!(NO_DBUS).into())
+    }
+}
@@ -0,0 +1,360 @@
+//! Low-level handling of the PNG files in the thumbnail cache.
+//!
+//! Rewriting the metadata of a thumbnail is done at the chunk level, copying the
+//! image data verbatim instead of decoding and re-encoding it. Images are only
+//! decoded when their pixels are actually needed.
+
+use crate::Error;
+use anyhow::{Context, Result};
+use pix::{el::Pixel, rgb::SRgba8, Raster};
+use png_pong::{Decoder, Encoder, PngRaster};
+use std::fmt;
+use std::fs::{remove_file, rename, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// An error for invalid PNG data, that can be told apart from I/O errors.
+pub(crate) fn invalid<E: fmt::Display>(e: E) -> anyhow::Error {
+    Error::PngDecode(e.to_string()).into()
+}
+
+/// Rewrite the PNG file in `data` with the text chunks in `text` (as key/value
+/// pairs).
+///
+/// Existing text chunks (tEXt, zTXt and iTXt) with the same keys are dropped,
+/// as well as all other text chunks if `keep_other` is false.
+pub(crate) fn rewrite_text(
+    data: &[u8],
+    text: &[(&str, &str)],
+    keep_other: bool,
+) -> Result<Vec<u8>> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(invalid("not a PNG file"));
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(SIGNATURE);
+
+    let mut rest = &data[SIGNATURE.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(invalid("truncated PNG file"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < len + 12 {
+            return Err(invalid("truncated PNG file"));
+        }
+        let (chunk, next) = rest.split_at(len + 12);
+        let name = &chunk[4..8];
+        rest = next;
+
+        if matches!(name, b"tEXt" | b"zTXt" | b"iTXt") {
+            let body = &chunk[8..8 + len];
+            let key = body.split(|b| *b == 0).next().unwrap_or_default();
+            if !keep_other || text.iter().any(|(k, _)| k.as_bytes() == key) {
+                continue;
+            }
+        }
+
+        out.extend_from_slice(chunk);
+
+        if name == b"IHDR" {
+            for (key, val) in text {
+                let mut body = Vec::with_capacity(key.len() + val.len() + 1);
+                body.extend_from_slice(key.as_bytes());
+                body.push(0);
+                body.extend_from_slice(val.as_bytes());
+                write_chunk(&mut out, b"tEXt", &body);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Read the width and height of the PNG file in `data`, from its header.
+pub(crate) fn dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(invalid("not a PNG file"));
+    }
+    let header = &data[SIGNATURE.len()..];
+    if header.len() < 16 || &header[4..8] != b"IHDR" {
+        return Err(invalid("missing PNG header"));
+    }
+
+    let width = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let height = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+    Ok((width, height))
+}
+
+fn write_chunk(out: &mut Vec<u8>, name: &[u8; 4], body: &[u8]) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(name);
+    crc.update(body);
+
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(name);
+    out.extend_from_slice(body);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Write a thumbnail to `path`, with the permissions required by the thumbnail
+/// spec.
+///
+/// The file is written to a temporary file first and then renamed, so that
+/// other programs never see a partially written thumbnail.
+pub(crate) fn write_thumbnail(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.to_path_buf();
+    tmp.set_extension(format!("thumbs-{}.tmp", std::process::id()));
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.to_string_lossy()))?;
+    if let Err(e) = file.write_all(data).and_then(|_| rename(&tmp, path)) {
+        let _ = remove_file(&tmp);
+        return Err(e).with_context(|| format!("Failed to write {}", path.to_string_lossy()));
+    }
+
+    Ok(())
+}
+
+/// A decoded image, as 8-bit RGBA pixels.
+pub(crate) struct Image {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Create an image filled with a single color.
+    pub(crate) fn with_color(width: u32, height: u32, color: [u8; 4]) -> Self {
+        Image {
+            width,
+            height,
+            pixels: color.repeat(width as usize * height as usize),
+        }
+    }
+
+    /// Decode the PNG file in `data`.
+    pub(crate) fn decode(data: &[u8]) -> Result<Self> {
+        let step = Decoder::new(data)
+            .map_err(invalid)?
+            .into_steps()
+            .next()
+            .ok_or_else(|| invalid("missing PNG image data"))?
+            .map_err(invalid)?;
+
+        let raster = match step.raster {
+            PngRaster::Gray8(r) => Raster::<SRgba8>::with_raster(&r),
+            PngRaster::Gray16(r) => Raster::with_raster(&r),
+            PngRaster::Rgb8(r) => Raster::with_raster(&r),
+            PngRaster::Rgb16(r) => Raster::with_raster(&r),
+            PngRaster::Graya8(r) => Raster::with_raster(&r),
+            PngRaster::Graya16(r) => Raster::with_raster(&r),
+            PngRaster::Rgba8(r) => r,
+            PngRaster::Rgba16(r) => Raster::with_raster(&r),
+            PngRaster::Palette(r, palette, alphas) => {
+                let pixels = r
+                    .as_u8_slice()
+                    .iter()
+                    .flat_map(|i| {
+                        let i = *i as usize;
+                        let color = palette.colors().get(i).copied().unwrap_or_default();
+                        let alpha = alphas.get(i).copied().unwrap_or(u8::MAX);
+                        [
+                            color.one().into(),
+                            color.two().into(),
+                            color.three().into(),
+                            alpha,
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                Raster::with_u8_buffer(r.width(), r.height(), pixels)
+            }
+        };
+
+        Ok(Image {
+            width: raster.width(),
+            height: raster.height(),
+            pixels: raster.as_u8_slice().to_vec(),
+        })
+    }
+
+    /// Encode the image as a PNG file.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let raster = Raster::<SRgba8>::with_u8_buffer(self.width, self.height, self.pixels.clone());
+
+        let mut out = Vec::new();
+        Encoder::new(&mut out).into_step_enc().still(&raster)?;
+        Ok(out)
+    }
+
+    /// The RGBA color of the pixel at `(x, y)`.
+    pub(crate) fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = self.offset(x, y);
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+
+    /// Set the color of the pixel at `(x, y)`, ignoring pixels outside of the
+    /// image.
+    pub(crate) fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
+        if x < self.width && y < self.height {
+            let i = self.offset(x, y);
+            self.pixels[i..i + 4].copy_from_slice(&color);
+        }
+    }
+
+    /// The offset of the pixel at `(x, y)` in `pixels`, computed in `usize` as
+    /// it overflows `u32` for large images.
+    fn offset(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * 4
+    }
+
+    /// Scale the image down (or up) to `width` x `height`, averaging the
+    /// pixels of the original image covered by each new pixel.
+    pub(crate) fn resize(&self, width: u32, height: u32) -> Image {
+        let mut out = Image::with_color(width, height, [0; 4]);
+        for y in 0..height {
+            let y0 = scale(y, self.height, height);
+            let y1 = scale(y + 1, self.height, height).max(y0 + 1);
+            for x in 0..width {
+                let x0 = scale(x, self.width, width);
+                let x1 = scale(x + 1, self.width, width).max(x0 + 1);
+
+                let mut sum = [0u64; 4];
+                for sy in y0..y1 {
+                    for sx in x0..x1 {
+                        let p = self.pixel(sx, sy);
+                        for c in 0..4 {
+                            sum[c] += p[c] as u64;
+                        }
+                    }
+                }
+                let n = (y1 - y0) as u64 * (x1 - x0) as u64;
+                out.set_pixel(x, y, sum.map(|c| (c / n) as u8));
+            }
+        }
+
+        out
+    }
+}
+
+/// Map the coordinate `v` in an image of size `to` to the same place in an
+/// image of size `from`, without overflowing.
+fn scale(v: u32, from: u32, to: u32) -> u32 {
+    (v as u64 * from as u64 / to as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names and bodies of the chunks of the PNG file in `data`, checking
+    /// their CRC.
+    fn chunks(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut rest = &data[SIGNATURE.len()..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (chunk, next) = rest.split_at(len + 12);
+            let crc = u32::from_be_bytes([
+                chunk[len + 8],
+                chunk[len + 9],
+                chunk[len + 10],
+                chunk[len + 11],
+            ]);
+            assert_eq!(crc32fast::hash(&chunk[4..len + 8]), crc);
+            chunks.push((
+                String::from_utf8_lossy(&chunk[4..8]).into_owned(),
+                chunk[8..len + 8].to_vec(),
+            ));
+            rest = next;
+        }
+
+        chunks
+    }
+
+    fn texts(data: &[u8]) -> Vec<Vec<u8>> {
+        chunks(data)
+            .into_iter()
+            .filter(|(name, _)| name == "tEXt")
+            .map(|(_, body)| body)
+            .collect()
+    }
+
+    fn image() -> Vec<u8> {
+        Image::with_color(4, 2, [255, 0, 0, 255]).encode().unwrap()
+    }
+
+    #[test]
+    fn rewrite_text_adds_chunks_after_header() {
+        let data = rewrite_text(&image(), &[("Thumb::URI", "file:///a")], true).unwrap();
+
+        let chunks = chunks(&data);
+        assert_eq!(chunks[0].0, "IHDR");
+        assert_eq!(
+            chunks[1],
+            ("tEXt".to_string(), b"Thumb::URI\0file:///a".to_vec())
+        );
+        assert_eq!(Image::decode(&data).unwrap().pixel(3, 1), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn rewrite_text_replaces_same_keys() {
+        let data = rewrite_text(
+            &image(),
+            &[("Thumb::URI", "file:///a"), ("Software", "thumbs")],
+            true,
+        )
+        .unwrap();
+        let data = rewrite_text(&data, &[("Thumb::URI", "file:///b")], true).unwrap();
+
+        assert_eq!(
+            texts(&data),
+            vec![
+                b"Thumb::URI\0file:///b".to_vec(),
+                b"Software\0thumbs".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrite_text_drops_other_chunks() {
+        let data = rewrite_text(&image(), &[("Software", "thumbs")], true).unwrap();
+
+        let stripped = rewrite_text(&data, &[], false).unwrap();
+        assert!(texts(&stripped).is_empty());
+        let replaced = rewrite_text(&data, &[("Thumb::MTime", "12")], false).unwrap();
+        assert_eq!(texts(&replaced), vec![b"Thumb::MTime\x0012".to_vec()]);
+    }
+
+    #[test]
+    fn rewrite_text_rejects_invalid_data() {
+        assert!(rewrite_text(b"GIF89a", &[], true).is_err());
+
+        let data = image();
+        let truncated = rewrite_text(&data[..data.len() - 4], &[], true).unwrap_err();
+        assert!(matches!(
+            truncated.downcast_ref::<Error>(),
+            Some(Error::PngDecode(_))
+        ));
+    }
+
+    #[test]
+    fn dimensions_from_header() {
+        assert_eq!(dimensions(&image()).unwrap(), (4, 2));
+        assert!(dimensions(SIGNATURE).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Showing thumbnails in the terminal.
+//!
+//! Images are shown with the graphics protocol of the terminal (kitty, sixel or
+//! iTerm2) when it can be detected, and with colored half-block characters
+//! otherwise.
+
+use crate::cli::ImageProtocol;
+use crate::png::Image;
+use crate::Error;
+use anyhow::Result;
+use std::env;
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// Assumed size of a terminal cell in pixels, when the terminal doesn't say.
+const CELL_SIZE: (u32, u32) = (8, 16);
+/// The kitty graphics protocol limits the payload of each escape sequence.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Guess the graphics protocol supported by the terminal, from the environment.
+pub fn detect_protocol() -> ImageProtocol {
+    let term = env::var("TERM").unwrap_or_default();
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+
+    if env::var_os("KITTY_WINDOW_ID").is_some()
+        || term == "xterm-kitty"
+        || term == "xterm-ghostty"
+        || program == "ghostty"
+    {
+        ImageProtocol::Kitty
+    } else if program == "iTerm.app" || program == "WezTerm" {
+        ImageProtocol::Iterm2
+    } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+        ImageProtocol::Sixel
+    } else {
+        ImageProtocol::Blocks
+    }
+}
+
+/// Show the PNG image in `data` on the terminal `out` with `protocol`, scaled
+/// down to fit in `columns` x `rows` terminal cells.
+pub fn show_image<W: Write>(
+    out: &mut W,
+    data: &[u8],
+    protocol: ImageProtocol,
+    columns: u32,
+    rows: u32,
+) -> Result<(), Error> {
+    let image = Image::decode(data)?;
+    let (cell_width, cell_height) = cell_size();
+
+    match protocol {
+        ImageProtocol::Blocks => {
+            // each cell shows two pixels, one above the other
+            let (width, height) = fit(&image, columns, rows * 2);
+            write_blocks(out, &image.resize(width, height))?;
+        }
+        _ => {
+            let (width, height) = fit(&image, columns * cell_width, rows * cell_height);
+            let image = image.resize(width, height);
+            match protocol {
+                ImageProtocol::Kitty => write_kitty(out, &image.encode()?)?,
+                ImageProtocol::Iterm2 => write_iterm2(out, &image.encode()?)?,
+                _ => write_sixel(out, &image)?,
+            }
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// The size of the terminal on stdout, in columns and rows.
+pub fn terminal_size() -> Option<(u32, u32)> {
+    let size = window_size()?;
+    if size.ws_col == 0 || size.ws_row == 0 {
+        return None;
+    }
+
+    Some((size.ws_col as u32, size.ws_row as u32))
+}
+
+fn window_size() -> Option<libc::winsize> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes to the winsize struct it is given
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return None;
+    }
+
+    Some(size)
+}
+
+/// The size of a terminal cell in pixels.
+fn cell_size() -> (u32, u32) {
+    match window_size() {
+        Some(s) if s.ws_col != 0 && s.ws_row != 0 && s.ws_xpixel != 0 && s.ws_ypixel != 0 => (
+            (s.ws_xpixel / s.ws_col).max(1) as u32,
+            (s.ws_ypixel / s.ws_row).max(1) as u32,
+        ),
+        _ => CELL_SIZE,
+    }
+}
+
+/// The size of `image` scaled down to fit in `max_width` x `max_height`, keeping
+/// its aspect ratio. Images are never scaled up.
+fn fit(image: &Image, max_width: u32, max_height: u32) -> (u32, u32) {
+    let (width, height) = (image.width.max(1), image.height.max(1));
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+
+    if width as u64 * max_height as u64 > height as u64 * max_width as u64 {
+        (max_width, (height * max_width / width).max(1))
+    } else {
+        ((width * max_height / height).max(1), max_height)
+    }
+}
+
+/// The color of `pixel` blended over a black background.
+fn blend(pixel: [u8; 4]) -> [u8; 3] {
+    let alpha = pixel[3] as u32;
+    [0, 1, 2].map(|c| (pixel[c] as u32 * alpha / 255) as u8)
+}
+
+fn write_blocks<W: Write>(out: &mut W, image: &Image) -> Result<()> {
+    for y in (0..image.height).step_by(2) {
+        let mut line = String::new();
+        for x in 0..image.width {
+            let top = image.pixel(x, y);
+            let bottom = if y + 1 < image.height {
+                image.pixel(x, y + 1)
+            } else {
+                [0; 4]
+            };
+            if top[3] < 128 && bottom[3] < 128 {
+                line.push_str("\x1b[0m ");
+                continue;
+            }
+            let (top, bottom) = (blend(top), blend(bottom));
+            write!(
+                line,
+                "\x1b[38;2;{};{};{};48;2;{};{};{}m\u{2580}",
+                top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+            )?;
+        }
+        writeln!(out, "{}\x1b[0m", line)?;
+    }
+
+    Ok(())
+}
+
+fn write_kitty<W: Write>(out: &mut W, png: &[u8]) -> Result<()> {
+    let data = base64::encode(png);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        // q=2 keeps the terminal from answering on stdin
+        if i == 0 {
+            write!(out, "\x1b_Ga=T,f=100,q=2,m={};", more)?;
+        } else {
+            write!(out, "\x1b_Gm={};", more)?;
+        }
+        out.write_all(chunk)?;
+        write!(out, "\x1b\\")?;
+    }
+
+    Ok(())
+}
+
+fn write_iterm2<W: Write>(out: &mut W, png: &[u8]) -> Result<()> {
+    write!(
+        out,
+        "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1:{}\x07",
+        png.len(),
+        base64::encode(png)
+    )?;
+
+    Ok(())
+}
+
+/// Write `image` as sixels, with colors reduced to a 6x6x6 color cube.
+fn write_sixel<W: Write>(out: &mut W, image: &Image) -> Result<()> {
+    let level = |c: u8| (c as u32 * 5 + 127) / 255;
+    let colors: Vec<u8> = (0..image.height)
+        .flat_map(|y| (0..image.width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let [r, g, b] = blend(image.pixel(x, y));
+            (level(r) * 36 + level(g) * 6 + level(b)) as u8
+        })
+        .collect();
+
+    let mut used = [false; 216];
+    for c in &colors {
+        used[*c as usize] = true;
+    }
+
+    let mut sixel = format!("\x1bPq\"1;1;{};{}", image.width, image.height);
+    for (c, _) in used.iter().enumerate().filter(|(_, u)| **u) {
+        let c = c as u32;
+        // color components are in percent
+        write!(
+            sixel,
+            "#{};2;{};{};{}",
+            c,
+            c / 36 * 20,
+            c / 6 % 6 * 20,
+            c % 6 * 20
+        )?;
+    }
+
+    let width = image.width as usize;
+    let height = image.height as usize;
+    for band in (0..height).step_by(6) {
+        let mut band_colors: Vec<u8> = (band..(band + 6).min(height))
+            .flat_map(|y| colors[y * width..(y + 1) * width].iter().copied())
+            .collect();
+        band_colors.sort_unstable();
+        band_colors.dedup();
+
+        for (i, color) in band_colors.iter().enumerate() {
+            if i != 0 {
+                // back to the start of the band
+                sixel.push('$');
+            }
+            write!(sixel, "#{}", color)?;
+
+            let row: Vec<char> = (0..width)
+                .map(|x| {
+                    let bits = (0..6)
+                        .filter(|dy| {
+                            band + dy < height && colors[(band + dy) * width + x] == *color
+                        })
+                        .fold(0, |bits, dy| bits | 1 << dy);
+                    (63 + bits) as u8 as char
+                })
+                .collect();
+            let mut x = 0;
+            while x < row.len() {
+                let run = row[x..].iter().take_while(|c| **c == row[x]).count();
+                if run > 3 {
+                    write!(sixel, "!{}{}", run, row[x])?;
+                } else {
+                    sixel.extend(std::iter::repeat_n(row[x], run));
+                }
+                x += run;
+            }
+        }
+        sixel.push('-');
+    }
+    sixel.push_str("\x1b\\");
+    out.write_all(sixel.as_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Reporting the progress of long operations on stderr.
+//!
+//! Progress is shown either as a progress bar redrawn on a single line, or as
+//! JSON documents (one per line) for graphical frontends.
+
+use crate::Progress;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two reports, except for the last one.
+const INTERVAL: Duration = Duration::from_millis(100);
+/// Width of the bar itself, in characters.
+const BAR_WIDTH: u64 = 24;
+
+/// Reports progress on stderr, at most every `INTERVAL`.
+#[derive(Debug)]
+pub struct ProgressReporter {
+    json: bool,
+    start: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last: Option<Instant>,
+    /// Whether the bar is currently shown.
+    drawn: bool,
+}
+
+impl ProgressReporter {
+    /// A progress bar, for a terminal.
+    pub fn bar() -> Self {
+        Self::new(false)
+    }
+
+    /// JSON documents with the fields of `Progress` and the `rate` of items
+    /// scanned per second.
+    pub fn json() -> Self {
+        Self::new(true)
+    }
+
+    fn new(json: bool) -> Self {
+        ProgressReporter {
+            json,
+            start: Instant::now(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn report(&self, progress: &Progress) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if !progress.finished && state.last.is_some_and(|l| now - l < INTERVAL) {
+            return;
+        }
+        state.last = Some(now);
+
+        let elapsed = (now - self.start).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            progress.done as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let err = io::stderr();
+        let mut err = err.lock();
+        // progress is best effort, failing to show it isn't worth stopping for
+        let _ = if self.json {
+            let report = serde_json::json!({
+                "done": progress.done,
+                "total": progress.total,
+                "found": progress.found,
+                "finished": progress.finished,
+                "rate": rate.round() as u64,
+            });
+            writeln!(err, "{}", report)
+        } else if progress.finished {
+            state.drawn = false;
+            write!(err, "\r\x1b[K")
+        } else {
+            state.drawn = true;
+            write!(err, "\r\x1b[K{}", render(progress, rate))
+        };
+        let _ = err.flush();
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        if self.state.lock().is_ok_and(|s| s.drawn) {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/// The progress bar line for `progress`, with `rate` items scanned per second.
+fn render(progress: &Progress, rate: f64) -> String {
+    let counts = format!("{} found, {:.0}/s", progress.found, rate);
+
+    match progress.total {
+        Some(total) if total != 0 => {
+            let done = progress.done.min(total);
+            let filled = (done * BAR_WIDTH / total) as usize;
+            format!(
+                "[{}{}] {:>3}% {}/{} scanned, {}",
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH as usize - filled),
+                done * 100 / total,
+                done,
+                total,
+                counts
+            )
+        }
+        _ => format!("{} scanned, {}", progress.done, counts),
+    }
+}
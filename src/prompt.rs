@@ -0,0 +1,380 @@
+//! Interactive confirmation of the deletion of thumbnails.
+
+use crate::cli::ImageProtocol;
+use crate::{human_size, preview, Error, Thumbnail};
+use anyhow::Result;
+use log::*;
+use std::io::{self, BufRead, Write};
+
+/// Number of entries per page in the review listing.
+const PAGE_SIZE: usize = 20;
+/// Maximum size of the preview of a thumbnail, in terminal cells.
+const PREVIEW_SIZE: (u32, u32) = (40, 20);
+
+const HELP: &str = "\
+Commands:
+  n, p          next / previous page
+  N, N-M, N M   toggle entries N (to M)
+  d N           toggle every entry in the directory of entry N
+  a, u          select / unselect all the listed entries
+  /TEXT         only list entries whose file contains TEXT ('/' alone to clear)
+  i N           show details about entry N
+  y             delete the selected thumbnails
+  q             quit without deleting anything";
+
+/// Ask the user to confirm the deletion of `thumbnails`, or to review them to
+/// only select some of them.
+///
+/// If `preview` is set, the details of a thumbnail during the review also show
+/// the thumbnail itself with this protocol.
+///
+/// Returns the thumbnails selected for deletion, or `None` if the user declined.
+pub fn confirm_deletion(
+    thumbnails: &[Thumbnail],
+    preview: Option<ImageProtocol>,
+) -> Result<Option<Vec<Thumbnail>>, Error> {
+    let answer = ask(&format!(
+        "Found {} thumbnail(s) to delete.\nDelete them? y(es) / N(o) / r(eview)> ",
+        thumbnails.len()
+    ))?;
+
+    match answer.as_str() {
+        "y" | "Y" => Ok(Some(thumbnails.to_vec())),
+        // 'd(etails)' was the previous way to list the files
+        "r" | "R" | "d" | "D" => Ok(Review::new(thumbnails, preview).run()?),
+        _ => Ok(None),
+    }
+}
+
+/// The state of the review of thumbnails to delete.
+struct Review<'a> {
+    thumbnails: &'a [Thumbnail],
+    /// Size of each thumbnail, if it could be read.
+    sizes: Vec<Option<u64>>,
+    selected: Vec<bool>,
+    /// Indices in `thumbnails`, sorted by file.
+    order: Vec<usize>,
+    filter: String,
+    /// Indices in `thumbnails` of the entries matching `filter`, sorted by file.
+    listed: Vec<usize>,
+    page: usize,
+    preview: Option<ImageProtocol>,
+}
+
+impl<'a> Review<'a> {
+    fn new(thumbnails: &'a [Thumbnail], preview: Option<ImageProtocol>) -> Self {
+        let mut order: Vec<usize> = (0..thumbnails.len()).collect();
+        order.sort_by(|&a, &b| thumbnails[a].file.cmp(&thumbnails[b].file));
+
+        Review {
+            thumbnails,
+            sizes: thumbnails
+                .iter()
+                .map(|t| t.thumbnail.metadata().ok().map(|m| m.len()))
+                .collect(),
+            selected: vec![true; thumbnails.len()],
+            listed: order.clone(),
+            order,
+            filter: String::new(),
+            page: 0,
+            preview,
+        }
+    }
+
+    fn run(mut self) -> Result<Option<Vec<Thumbnail>>> {
+        self.print_page()?;
+        loop {
+            let answer = ask("Review (? for help)> ")?;
+            let (cmd, arg) = match answer.split_once(' ') {
+                Some((c, a)) => (c, a.trim()),
+                None => (answer.as_str(), ""),
+            };
+
+            match cmd {
+                "y" | "Y" => {
+                    let selected = self
+                        .thumbnails
+                        .iter()
+                        .zip(&self.selected)
+                        .filter(|(_, s)| **s)
+                        .map(|(t, _)| t.clone())
+                        .collect();
+                    return Ok(Some(selected));
+                }
+                "q" | "Q" | "" => return Ok(None),
+                "?" | "h" => println!("{}", HELP),
+                "n" => {
+                    if (self.page + 1) * PAGE_SIZE < self.listed.len() {
+                        self.page += 1;
+                    }
+                    self.print_page()?;
+                }
+                "p" => {
+                    self.page = self.page.saturating_sub(1);
+                    self.print_page()?;
+                }
+                "a" | "u" => {
+                    for &i in &self.listed {
+                        self.selected[i] = cmd == "a";
+                    }
+                    self.print_page()?;
+                }
+                "d" => match self.entry(arg) {
+                    Some(i) => {
+                        self.toggle_dir(i);
+                        self.print_page()?;
+                    }
+                    None => warn!("Invalid entry: '{}'", arg),
+                },
+                "i" => match self.entry(arg) {
+                    Some(i) => self.print_details(i)?,
+                    None => warn!("Invalid entry: '{}'", arg),
+                },
+                _ if cmd.starts_with('/') => {
+                    self.set_filter(answer[1..].to_string());
+                    self.print_page()?;
+                }
+                _ => match self.entries(&answer) {
+                    Some(entries) => {
+                        for i in entries {
+                            self.selected[i] = !self.selected[i];
+                        }
+                        self.print_page()?;
+                    }
+                    None => warn!("Unknown command '{}'. Type '?' for help.", answer),
+                },
+            }
+        }
+    }
+
+    fn set_filter(&mut self, filter: String) {
+        self.listed = self
+            .order
+            .iter()
+            .copied()
+            .filter(|&i| {
+                self.thumbnails[i]
+                    .file
+                    .to_string_lossy()
+                    .contains(filter.as_str())
+            })
+            .collect();
+        self.filter = filter;
+        self.page = 0;
+    }
+
+    /// Toggle every entry in the directory of the file of entry `i`, to the
+    /// opposite of the state of entry `i`.
+    fn toggle_dir(&mut self, i: usize) {
+        let dir = self.thumbnails[i].file.parent();
+        let select = !self.selected[i];
+        for (t, s) in self.thumbnails.iter().zip(self.selected.iter_mut()) {
+            if t.file.parent() == dir {
+                *s = select;
+            }
+        }
+    }
+
+    /// The index in `thumbnails` of the listed entry numbered `arg`.
+    fn entry(&self, arg: &str) -> Option<usize> {
+        let n: usize = arg.parse().ok()?;
+        self.listed.get(n.checked_sub(1)?).copied()
+    }
+
+    /// The indices in `thumbnails` of the listed entries in `arg`, a list of
+    /// entry numbers or ranges like `3-7`.
+    fn entries(&self, arg: &str) -> Option<Vec<usize>> {
+        let mut entries = Vec::new();
+        for part in arg.split(|c: char| c == ',' || c.is_whitespace()) {
+            if part.is_empty() {
+                continue;
+            }
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let start: usize = start.parse().ok()?;
+                    let end: usize = end.parse().ok()?;
+                    for n in start..=end {
+                        entries.push(self.entry(&n.to_string())?);
+                    }
+                }
+                None => entries.push(self.entry(part)?),
+            }
+        }
+
+        if entries.is_empty() {
+            None
+        } else {
+            Some(entries)
+        }
+    }
+
+    fn print_page(&self) -> Result<()> {
+        let out = io::stdout();
+        let mut out = out.lock();
+
+        let start = self.page * PAGE_SIZE;
+        for (n, &i) in self.listed.iter().enumerate().skip(start).take(PAGE_SIZE) {
+            writeln!(
+                out,
+                "[{}] {:>4}  {} ({})",
+                if self.selected[i] { 'x' } else { ' ' },
+                n + 1,
+                self.thumbnails[i].file.to_string_lossy(),
+                self.size(i)
+            )?;
+        }
+
+        let nb_selected = self.selected.iter().filter(|s| **s).count();
+        let size: u64 = self
+            .sizes
+            .iter()
+            .zip(&self.selected)
+            .filter(|(_, s)| **s)
+            .filter_map(|(size, _)| *size)
+            .sum();
+        write!(
+            out,
+            "Page {}/{}, {} of {} thumbnail(s) selected ({})",
+            self.page + 1,
+            self.listed.len().div_ceil(PAGE_SIZE).max(1),
+            nb_selected,
+            self.thumbnails.len(),
+            human_size(size)
+        )?;
+        if !self.filter.is_empty() {
+            write!(out, ", {} matching '{}'", self.listed.len(), self.filter)?;
+        }
+        writeln!(out)?;
+        out.flush()?;
+
+        Ok(())
+    }
+
+    fn print_details(&self, i: usize) -> Result<()> {
+        let thumb = &self.thumbnails[i];
+        let out = io::stdout();
+        let mut out = out.lock();
+        writeln!(out, "File:      {}", thumb.file.to_string_lossy())?;
+        writeln!(out, "Thumbnail: {}", thumb.thumbnail.to_string_lossy())?;
+        writeln!(out, "Size:      {}", self.size(i))?;
+        if let Some(app) = &thumb.app {
+            writeln!(out, "App:       {}", app)?;
+        }
+        writeln!(
+            out,
+            "Selected:  {}",
+            if self.selected[i] { "yes" } else { "no" }
+        )?;
+        if let Some(protocol) = self.preview {
+            let (columns, rows) = preview::terminal_size().unwrap_or(PREVIEW_SIZE);
+            let shown = std::fs::read(&thumb.thumbnail)
+                .map_err(Into::into)
+                .and_then(|data| {
+                    preview::show_image(
+                        &mut out,
+                        &data,
+                        protocol,
+                        columns.min(PREVIEW_SIZE.0),
+                        rows.min(PREVIEW_SIZE.1),
+                    )
+                });
+            if let Err(e) = shown {
+                warn!(
+                    "Failed to show {}: {}",
+                    thumb.thumbnail.to_string_lossy(),
+                    e
+                );
+            }
+        }
+        out.flush()?;
+
+        Ok(())
+    }
+
+    fn size(&self, i: usize) -> String {
+        match self.sizes[i] {
+            Some(s) => human_size(s),
+            None => "missing".to_string(),
+        }
+    }
+}
+
+/// Print `prompt` and read a line from stdin, without the trailing newline.
+fn ask(prompt: &str) -> Result<String> {
+    {
+        let out = io::stdout();
+        let mut out = out.lock();
+        write!(out, "{}", prompt)?;
+        out.flush()?;
+    }
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    trace!("read user input: {:?}", answer);
+
+    Ok(answer.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn thumbnails(files: &[&str]) -> Vec<Thumbnail> {
+        files
+            .iter()
+            .map(|f| Thumbnail {
+                thumbnail: PathBuf::from(format!("/nonexistent/{}.png", f.len())),
+                file: PathBuf::from(f),
+                app: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entries_are_numbered_by_file() {
+        let thumbnails = thumbnails(&["/b", "/a", "/c"]);
+        let review = Review::new(&thumbnails, None);
+
+        assert_eq!(review.entries("1"), Some(vec![1]));
+        assert_eq!(review.entries("3 1"), Some(vec![2, 1]));
+        assert_eq!(review.entries("1-2, 3"), Some(vec![1, 0, 2]));
+        assert_eq!(review.entries("2-2"), Some(vec![0]));
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let thumbnails = thumbnails(&["/b", "/a", "/c"]);
+        let review = Review::new(&thumbnails, None);
+
+        assert_eq!(review.entries(""), None);
+        assert_eq!(review.entries("0"), None);
+        assert_eq!(review.entries("4"), None);
+        assert_eq!(review.entries("2-4"), None);
+        assert_eq!(review.entries("3-1"), None);
+        assert_eq!(review.entries("1-"), None);
+        assert_eq!(review.entries("a"), None);
+    }
+
+    #[test]
+    fn entries_are_numbered_after_filtering() {
+        let thumbnails = thumbnails(&["/x/b", "/y/a", "/x/c"]);
+        let mut review = Review::new(&thumbnails, None);
+        review.set_filter("/x/".to_string());
+
+        assert_eq!(review.entries("1-2"), Some(vec![0, 2]));
+        assert_eq!(review.entries("3"), None);
+    }
+
+    #[test]
+    fn toggle_directory() {
+        let thumbnails = thumbnails(&["/x/b", "/y/a", "/x/c"]);
+        let mut review = Review::new(&thumbnails, None);
+
+        review.toggle_dir(0);
+        assert_eq!(review.selected, vec![false, true, false]);
+        review.selected[2] = true;
+        review.toggle_dir(2);
+        assert_eq!(review.selected, vec![false, true, false]);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::journal::JournalAction;
+use crate::{Error, FailedThumbnail, Thumbnail, UnThumbnailer};
+use anyhow::{Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{
+    copy, read_dir, remove_dir, remove_dir_all, remove_file, rename, write, DirBuilder, File,
+    OpenOptions,
+};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Path of the quarantine, relative to the data directory.
+const QUARANTINE_PATH: &str = "thumbs/quarantine";
+/// Name of the manifest of an operation, with one JSON entry per line.
+const MANIFEST_NAME: &str = "manifest.jsonl";
+/// Quarantined thumbnails older than this are deleted for good.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A thumbnail in the manifest of an operation.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    /// Name of the thumbnail in the operation directory
+    name: String,
+    /// Original path of the thumbnail
+    thumbnail: PathBuf,
+    file: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    app: Option<String>,
+}
+
+/// A holding area for deleted thumbnails, with a directory for each operation.
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    root: PathBuf,
+    /// The directory of the current operation, and the number of thumbnails in it.
+    /// Created on the first deletion, and removed again if that deletion fails.
+    operation: Mutex<Option<(PathBuf, u32)>>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UndoResults {
+    pub restored: Vec<Thumbnail>,
+    /// Thumbnails that were regenerated since.
+    pub existing: u32,
+    /// Thumbnails that couldn't be restored. They are kept in quarantine, for
+    /// the next `undo`.
+    pub failed: Vec<FailedThumbnail>,
+}
+
+impl Quarantine {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        Quarantine {
+            root,
+            operation: Mutex::new(None),
+        }
+    }
+
+    /// The default quarantine, in `$XDG_DATA_HOME/thumbs/quarantine`.
+    pub(crate) fn default_root() -> Result<PathBuf> {
+        let mut root = dirs::data_dir().ok_or(Error::NoDirectory("data"))?;
+        root.push(QUARANTINE_PATH);
+        Ok(root)
+    }
+
+    /// The quarantine of the user with the home directory `home`.
+    ///
+    /// The `XDG_DATA_HOME` of another user isn't known, so this is always in its
+    /// default location, `~/.local/share`.
+    pub(crate) fn for_user(home: &Path) -> Self {
+        Quarantine::new(home.join(".local/share").join(QUARANTINE_PATH))
+    }
+
+    /// Move the thumbnail `thumb` to the directory of the current operation.
+    pub(crate) fn add(&self, thumb: &Thumbnail) -> Result<()> {
+        let mut operation = self.operation.lock().unwrap();
+        let (dir, count) = match &*operation {
+            Some((dir, count)) => (dir.clone(), *count),
+            None => (self.create_operation()?, 0),
+        };
+
+        let entry = ManifestEntry {
+            name: format!("{}.png", count + 1),
+            thumbnail: thumb.thumbnail.clone(),
+            file: thumb.file.clone(),
+            app: thumb.app.clone(),
+        };
+        let quarantined = dir.join(&entry.name);
+        if let Err(e) = add_entry(&dir, &quarantined, &entry) {
+            if count == 0 {
+                // an empty operation would be undone instead of the previous one
+                // (this fails if the thumbnail couldn't be moved back)
+                let _ = remove_file(dir.join(MANIFEST_NAME));
+                if let Err(e) = remove_dir(&dir) {
+                    debug!("{} for {}", e, dir.to_string_lossy());
+                }
+            }
+            return Err(e);
+        }
+        *operation = Some((dir, count + 1));
+
+        Ok(())
+    }
+
+    fn create_operation(&self) -> Result<PathBuf> {
+        self.prune();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let name = format!("{}-{}", now, std::process::id());
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.root)
+            .with_context(|| format!("Failed to create {}", self.root.to_string_lossy()))?;
+        // another operation may have started in the same millisecond
+        let mut dir = self.root.join(&name);
+        let mut n = 0;
+        loop {
+            match DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    dir = self.root.join(format!("{}-{}", name, n));
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::from(e)
+                        .context(format!("Failed to create {}", dir.to_string_lossy())))
+                }
+            }
+        }
+        debug!("Quarantining thumbnails to {}", dir.to_string_lossy());
+
+        Ok(dir)
+    }
+
+    /// Delete the operations older than `MAX_AGE`.
+    fn prune(&self) {
+        let limit = SystemTime::now()
+            .checked_sub(MAX_AGE)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |t| t.as_millis());
+        for (time, dir) in operations(&self.root).unwrap_or_default() {
+            if time < limit {
+                debug!("Removing old quarantine {}", dir.to_string_lossy());
+                if let Err(e) = remove_dir_all(&dir) {
+                    debug!("{} for {}", e, dir.to_string_lossy());
+                }
+            }
+        }
+    }
+}
+
+impl UnThumbnailer {
+    /// Move deleted thumbnails to a holding area in `$XDG_DATA_HOME/thumbs/quarantine`
+    /// instead of deleting them, so that the operation can be reverted with `undo`.
+    ///
+    /// Quarantined thumbnails are deleted for good after 30 days.
+    pub fn enable_quarantine(&mut self) -> Result<(), Error> {
+        self.quarantine = Some(Quarantine::new(Quarantine::default_root()?));
+
+        Ok(())
+    }
+
+    /// Delete the thumbnails `thumbs`, or move them to quarantine if enabled.
+    ///
+    /// Returns the thumbnails that couldn't be deleted.
+    pub fn delete_thumbnails(&self, thumbs: &[Thumbnail]) -> Vec<FailedThumbnail> {
+        let mut failed = Vec::new();
+        for thumb in thumbs {
+            if let Err(e) = self.remove_thumbnail(thumb) {
+                debug!(
+                    "Failed to delete {}: {}",
+                    thumb.thumbnail.to_string_lossy(),
+                    e
+                );
+                failed.push(FailedThumbnail::new(thumb.clone(), &e));
+            }
+        }
+
+        failed
+    }
+
+    /// Restore the thumbnails deleted with quarantine enabled by the last
+    /// operation.
+    ///
+    /// Thumbnails that were regenerated since are left alone. Thumbnails that
+    /// can't be restored are reported, and kept for the next `undo`. Returns
+    /// `None` if there is nothing to undo.
+    pub fn undo(&self) -> Result<Option<UndoResults>, Error> {
+        let root = match &self.quarantine {
+            Some(q) => q.root.clone(),
+            None => Quarantine::default_root()?,
+        };
+        let dir = match operations(&root)?.pop() {
+            Some((_, dir)) => dir,
+            None => return Ok(None),
+        };
+
+        let mut results = UndoResults::default();
+        // the entries that couldn't be restored, kept for another try
+        let mut remaining = Vec::new();
+        let manifest = dir.join(MANIFEST_NAME);
+        if manifest.exists() {
+            for line in BufReader::new(File::open(&manifest)?).lines() {
+                let line = line?;
+                let entry: ManifestEntry = match serde_json::from_str(&line) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        warn!("Invalid quarantine manifest entry: {}", e);
+                        remaining.push(line);
+                        continue;
+                    }
+                };
+                if entry.thumbnail.exists() {
+                    debug!(
+                        "Skipping a thumbnail for '{}' that was regenerated",
+                        entry.file.to_string_lossy()
+                    );
+                    results.existing += 1;
+                    continue;
+                }
+
+                let thumb = Thumbnail {
+                    thumbnail: entry.thumbnail,
+                    file: entry.file,
+                    app: entry.app,
+                };
+                match self.restore_quarantined(&dir.join(&entry.name), &thumb) {
+                    Ok(()) => results.restored.push(thumb),
+                    Err(e) => {
+                        debug!(
+                            "Failed to restore {}: {}",
+                            thumb.thumbnail.to_string_lossy(),
+                            e
+                        );
+                        results.failed.push(FailedThumbnail::new(thumb, &e));
+                        remaining.push(line);
+                    }
+                }
+            }
+        }
+
+        if remaining.is_empty() {
+            remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove {}", dir.to_string_lossy()))?;
+        } else {
+            let tmp = manifest.with_extension("jsonl.tmp");
+            write(&tmp, remaining.join("\n") + "\n")
+                .and_then(|_| rename(&tmp, &manifest))
+                .with_context(|| format!("Failed to write {}", manifest.to_string_lossy()))?;
+        }
+
+        Ok(Some(results))
+    }
+
+    /// Move the quarantined thumbnail at `quarantined` back to its place.
+    fn restore_quarantined(&self, quarantined: &Path, thumb: &Thumbnail) -> Result<()> {
+        info!(
+            "Restoring a thumbnail for '{}'",
+            thumb.file.to_string_lossy()
+        );
+        if let Some(parent) = thumb.thumbnail.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        let moved = move_file(quarantined, &thumb.thumbnail);
+        self.record(
+            JournalAction::Restore,
+            &thumb.thumbnail,
+            Some(&thumb.file),
+            None,
+            &moved,
+        );
+
+        moved
+    }
+
+    /// Delete the thumbnail `thumb`, or move it to quarantine if enabled.
+    pub(crate) fn remove_thumbnail(&self, thumb: &Thumbnail) -> Result<()> {
+        self.run_action(self.delete_action(), thumb)
+    }
+}
+
+/// The directories of the past operations in the quarantine at `root`, with their
+/// time in milliseconds since the epoch, most recent last.
+fn operations(root: &Path) -> Result<Vec<(u128, PathBuf)>> {
+    let entries = match read_dir(root) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut operations = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let time = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.split('-').next())
+            .and_then(|t| t.parse().ok());
+        if let Some(time) = time {
+            operations.push((time, entry.path()));
+        }
+    }
+    operations.sort();
+
+    Ok(operations)
+}
+
+/// Move the thumbnail of `entry` to `quarantined`, in the operation directory
+/// `dir`, and add `entry` to the manifest.
+fn add_entry(dir: &Path, quarantined: &Path, entry: &ManifestEntry) -> Result<()> {
+    move_file(&entry.thumbnail, quarantined)?;
+
+    let appended = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(dir.join(MANIFEST_NAME))
+        .map_err(anyhow::Error::from)
+        .and_then(|mut m| {
+            writeln!(m, "{}", serde_json::to_string(entry)?)?;
+            Ok(())
+        });
+    if let Err(e) = appended {
+        // undo couldn't restore a thumbnail missing from the manifest
+        if let Err(back) = move_file(quarantined, &entry.thumbnail) {
+            warn!(
+                "Failed to move {} back to {}: {:#}",
+                quarantined.to_string_lossy(),
+                entry.thumbnail.to_string_lossy(),
+                back
+            );
+        }
+        return Err(e.context("Failed to write the quarantine manifest"));
+    }
+
+    Ok(())
+}
+
+/// Move the file at `from` to `to`, even across file systems.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if rename(from, to).is_err() {
+        copy(from, to)?;
+        remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{enable_journal, journal_actions, thumbnailed_file, TempDir};
+    use std::fs::read;
+
+    /// An `UnThumbnailer` quarantining thumbnails in `dir`, and the thumbnails of
+    /// the files `names` in its cache.
+    fn quarantined(dir: &Path, names: &[&str]) -> (UnThumbnailer, Vec<Thumbnail>) {
+        let cache = dir.join("cache");
+        let thumbs = names
+            .iter()
+            .map(|name| {
+                let file = dir.join(name);
+                Thumbnail {
+                    thumbnail: thumbnailed_file(&cache, &file),
+                    file,
+                    app: None,
+                }
+            })
+            .collect();
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        un.quarantine = Some(Quarantine::new(dir.join("quarantine")));
+        enable_journal(&mut un, dir);
+
+        (un, thumbs)
+    }
+
+    #[test]
+    fn undo_deletion() {
+        let dir = TempDir::new("quarantine-undo");
+        let (un, thumbs) = quarantined(dir.path(), &["a", "b"]);
+        let data = read(&thumbs[0].thumbnail).unwrap();
+
+        assert!(un.delete_thumbnails(&thumbs).is_empty());
+        assert!(thumbs.iter().all(|t| !t.thumbnail.exists()));
+        assert_eq!(operations(&dir.path().join("quarantine")).unwrap().len(), 1);
+        // regenerated since
+        thumbnailed_file(&dir.path().join("cache"), &thumbs[1].file);
+
+        let results = un.undo().unwrap().unwrap();
+        assert_eq!(results.restored.len(), 1);
+        assert_eq!(results.restored[0].thumbnail, thumbs[0].thumbnail);
+        assert_eq!(results.existing, 1);
+        assert!(results.failed.is_empty());
+        assert_eq!(read(&thumbs[0].thumbnail).unwrap(), data);
+        assert!(operations(&dir.path().join("quarantine"))
+            .unwrap()
+            .is_empty());
+        let actions: Vec<_> = journal_actions(&un).into_iter().map(|(a, _)| a).collect();
+        assert_eq!(
+            actions,
+            [
+                JournalAction::Quarantine,
+                JournalAction::Quarantine,
+                JournalAction::Restore
+            ]
+        );
+
+        assert!(un.undo().unwrap().is_none());
+    }
+
+    #[test]
+    fn no_empty_operation() {
+        let dir = TempDir::new("quarantine-failed");
+        let (un, thumbs) = quarantined(dir.path(), &["a"]);
+        assert!(un.delete_thumbnails(&thumbs).is_empty());
+
+        // a new operation whose first deletion fails
+        let (un, _) = quarantined(dir.path(), &[]);
+        let missing = Thumbnail {
+            thumbnail: dir.path().join("cache/normal/missing.png"),
+            file: dir.path().join("missing"),
+            app: None,
+        };
+        assert_eq!(un.delete_thumbnails(&[missing]).len(), 1);
+        assert_eq!(operations(&dir.path().join("quarantine")).unwrap().len(), 1);
+
+        // the previous operation is undone
+        let results = un.undo().unwrap().unwrap();
+        assert_eq!(results.restored.len(), 1);
+        assert!(thumbs[0].thumbnail.exists());
+    }
+}
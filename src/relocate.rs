@@ -0,0 +1,616 @@
+use crate::journal::JournalAction;
+use crate::sandbox::thumbnail_app;
+use crate::{
+    file_url, is_hidden_unix, is_missing, png, read_metadata, thumbnail_file_name, CacheEntry,
+    Error, Thumbnail, UnThumbnailer,
+};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::Serialize;
+use std::fs::{metadata, read, remove_file, rename, File};
+use std::io::{BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use url::Url;
+use walkdir::WalkDir;
+
+/// A thumbnail that was carried over from a file to its new location (or copied
+/// to a copy of the file).
+#[derive(Debug, Clone, Serialize)]
+pub struct MovedThumbnail {
+    pub from: Thumbnail,
+    pub to: Thumbnail,
+}
+
+impl UnThumbnailer {
+    /// Move the file (or directory) at `from` to `to`, carrying its thumbnails
+    /// along.
+    ///
+    /// Like `mv`, if `to` is an existing directory, the file is moved inside it.
+    /// Unlike `mv`, it can't be moved to another filesystem. Failing to move the
+    /// thumbnails once the file was moved is only a warning.
+    pub fn move_file(&self, from: &Path, to: &Path) -> Result<Vec<MovedThumbnail>, Error> {
+        let from = absolute_path(from)?;
+        let mut to = absolute_path(to)?;
+        if to.is_dir() {
+            to.push(
+                from.file_name()
+                    .ok_or_else(|| anyhow!("Cannot move {}", from.to_string_lossy()))?,
+            );
+        }
+
+        // renaming only works on the same filesystem, and copying would lose the
+        // modification times the thumbnails are valid for
+        let to_parent = to.parent().unwrap_or(&to);
+        if metadata(&from)?.dev() != metadata(to_parent)?.dev() {
+            return Err(cross_device(&from, &to).into());
+        }
+
+        info!(
+            "Moving '{}' to '{}'",
+            from.to_string_lossy(),
+            to.to_string_lossy()
+        );
+        rename(&from, &to).map_err(|e| {
+            if e.raw_os_error() == Some(libc::EXDEV) {
+                cross_device(&from, &to)
+            } else {
+                anyhow::Error::new(e).context(format!(
+                    "Failed to move {} to {}",
+                    from.to_string_lossy(),
+                    to.to_string_lossy()
+                ))
+            }
+        })?;
+
+        let moved = if to.is_dir() {
+            self.relocate(&from, &to, false)
+        } else {
+            self.move_thumbnails(&from, &to, false)
+        };
+        // the file was moved anyway
+        Ok(moved.unwrap_or_else(|e| {
+            warn!(
+                "Failed to move the thumbnails of '{}': {:#}",
+                from.to_string_lossy(),
+                anyhow::Error::from(e)
+            );
+            Vec::new()
+        }))
+    }
+
+    /// Carry the thumbnails of the file that was at `from` over to its new
+    /// location at `to`. `dry_run` only reports results but doesn't actually
+    /// move anything.
+    pub fn move_thumbnails(
+        &self,
+        from: &Path,
+        to: &Path,
+        dry_run: bool,
+    ) -> Result<Vec<MovedThumbnail>, Error> {
+        let from = absolute_path(from)?;
+        let to = absolute_path(to)?;
+        let name = thumbnail_file_name(&file_url(&from)?);
+        let to_url = file_url(&to)?;
+
+        let mut moved = Vec::new();
+        for location in &self.cache_locs {
+            let thumb = location.join(&name);
+            if thumb.exists() {
+                let th = Thumbnail {
+                    app: thumbnail_app(&thumb),
+                    thumbnail: thumb,
+                    file: from.clone(),
+                };
+                let thumbnail = th.thumbnail.clone();
+                match carry_thumbnail(th, &to, &to_url, None, false, dry_run, self) {
+                    Ok(m) => moved.extend(m),
+                    Err(e) => warn!("Failed to move {}: {:#}", thumbnail.to_string_lossy(), e),
+                }
+            }
+        }
+
+        if moved.is_empty() {
+            debug!(
+                "Could not find a thumbnail for '{}'",
+                from.to_string_lossy()
+            );
+        }
+
+        Ok(moved)
+    }
+
+    /// Carry the thumbnails of all files under the directory `from` over to the
+    /// same files under `to`, for files that were already moved there. `dry_run`
+    /// only reports results but doesn't actually move anything.
+    pub fn relocate(
+        &self,
+        from: &Path,
+        to: &Path,
+        dry_run: bool,
+    ) -> Result<Vec<MovedThumbnail>, Error> {
+        let from = absolute_path(from)?;
+        let to = absolute_path(to)?;
+
+        let mut moved = Vec::new();
+        for entry in self.scan().filter_map(|e| e.ok()) {
+            match relocate_thumbnail(&entry, &from, &to, dry_run, self) {
+                Ok(Some(m)) => moved.push(m),
+                Ok(None) => {}
+                Err(e) => debug!("{} for {}", e, entry.thumbnail().to_string_lossy()),
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Copy the thumbnails of the files at `from` to their copies at `to`,
+    /// possibly recursing in directories if enabled.
+    ///
+    /// Thumbnails are only copied for files that exist at both locations with
+    /// the same content, and that don't already have a thumbnail.
+    pub fn copy_thumbnails(&self, from: &Path, to: &Path) -> Result<Vec<MovedThumbnail>, Error> {
+        let from = absolute_path(from)?;
+        let to = absolute_path(to)?;
+
+        let mut copied = Vec::new();
+        if from.is_file() {
+            self.copy_file_thumbnails(&from, &to, &mut copied)?;
+        } else {
+            let mut walk = WalkDir::new(&from).min_depth(1);
+            if !self.recursive {
+                walk = walk.max_depth(1);
+            }
+            for entry in walk
+                .into_iter()
+                .filter_entry(|e| self.hidden || !is_hidden_unix(e.file_name()))
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+            {
+                let dest = to.join(entry.path().strip_prefix(&from)?);
+                if let Err(e) = self.copy_file_thumbnails(entry.path(), &dest, &mut copied) {
+                    debug!("{} for {}", e, entry.path().to_string_lossy());
+                }
+            }
+        }
+
+        Ok(copied)
+    }
+
+    fn copy_file_thumbnails(
+        &self,
+        from: &Path,
+        to: &Path,
+        acc: &mut Vec<MovedThumbnail>,
+    ) -> Result<()> {
+        trace!("Processing {:?}", from);
+        if !to.is_file() {
+            debug!("'{}' was not copied, ignoring.", to.to_string_lossy());
+            return Ok(());
+        }
+        if !same_content(from, to)? {
+            debug!(
+                "'{}' differs from '{}', ignoring.",
+                to.to_string_lossy(),
+                from.to_string_lossy()
+            );
+            return Ok(());
+        }
+        // the copy is only valid for the modification time of the new file
+        let mtime = modified_secs(from)?;
+        let new_mtime = modified_secs(to)?;
+        let new_mtime = if mtime != new_mtime {
+            Some(new_mtime.to_string())
+        } else {
+            None
+        };
+
+        let name = thumbnail_file_name(&file_url(from)?);
+        let to_url = file_url(to)?;
+        let new_name = thumbnail_file_name(&to_url);
+        for location in &self.cache_locs {
+            let thumb = location.join(&name);
+            if !thumb.exists() {
+                continue;
+            }
+            if location.join(&new_name).exists() {
+                debug!("'{}' already has a thumbnail.", to.to_string_lossy());
+                continue;
+            }
+
+            let th = Thumbnail {
+                app: thumbnail_app(&thumb),
+                thumbnail: thumb,
+                file: from.to_path_buf(),
+            };
+            acc.extend(carry_thumbnail(
+                th,
+                to,
+                &to_url,
+                new_mtime.as_deref(),
+                true,
+                false,
+                self,
+            )?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if the files at `a` and `b` have the same content, either because they
+/// have the same size and modification time, or by comparing them.
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    if metadata(a)?.len() != metadata(b)?.len() {
+        return Ok(false);
+    }
+    if modified_secs(a)? == modified_secs(b)? {
+        return Ok(true);
+    }
+
+    let mut file_a = BufReader::new(File::open(a)?);
+    let mut file_b = BufReader::new(File::open(b)?);
+    let mut buf_a = [0; 8192];
+    let mut buf_b = [0; 8192];
+    loop {
+        let n = file_a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        file_b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// The modification time of the file at `path`, in seconds since the epoch (as
+/// stored in `Thumb::MTime`).
+fn modified_secs(path: &Path) -> Result<u64> {
+    Ok(metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs())
+}
+
+fn relocate_thumbnail(
+    entry: &CacheEntry,
+    from: &Path,
+    to: &Path,
+    dry_run: bool,
+    un: &UnThumbnailer,
+) -> Result<Option<MovedThumbnail>> {
+    trace!("Processing {:?}", entry.thumbnail());
+    let th = match entry.to_thumbnail()? {
+        Some(th) => th,
+        None => return Ok(None),
+    };
+    let origin_path = &th.file;
+    let new_path = match origin_path.strip_prefix(from) {
+        Ok(rel) => to.join(rel),
+        Err(_) => return Ok(None),
+    };
+    if !is_missing(origin_path) {
+        debug!(
+            "'{}' is still there, ignoring.",
+            origin_path.to_string_lossy()
+        );
+        return Ok(None);
+    }
+    if !new_path.exists() {
+        debug!(
+            "'{}' was not moved to '{}', ignoring.",
+            origin_path.to_string_lossy(),
+            new_path.to_string_lossy()
+        );
+        return Ok(None);
+    }
+
+    let new_url = file_url(&new_path)?;
+
+    carry_thumbnail(th, &new_path, &new_url, None, false, dry_run, un)
+}
+
+/// Move (or copy if `copy` is set) the thumbnail `thumb` for the file at `to`,
+/// and rewrite its `Thumb::URI` (and `Thumb::MTime` if given) to match.
+///
+/// A thumbnail `to` already has is kept if it is valid for it, in which case
+/// `None` is returned and `thumb` is deleted (unless `copy` is set).
+fn carry_thumbnail(
+    thumb: Thumbnail,
+    to: &Path,
+    to_url: &Url,
+    mtime: Option<&str>,
+    copy: bool,
+    dry_run: bool,
+    un: &UnThumbnailer,
+) -> Result<Option<MovedThumbnail>> {
+    let new_thumb = thumb.thumbnail.with_file_name(thumbnail_file_name(to_url));
+    let (verb, verb_ing) = if copy {
+        ("copy", "Copying")
+    } else {
+        ("move", "Moving")
+    };
+
+    if new_thumb != thumb.thumbnail && is_up_to_date(&new_thumb, to) {
+        debug!(
+            "'{}' already has an up to date thumbnail.",
+            to.to_string_lossy()
+        );
+        if !copy {
+            if dry_run {
+                info!(
+                    "Would delete the old thumbnail for '{}'",
+                    thumb.file.to_string_lossy()
+                );
+            } else {
+                info!(
+                    "Deleting the old thumbnail for '{}'",
+                    thumb.file.to_string_lossy()
+                );
+                un.remove_thumbnail(&thumb)?;
+            }
+        }
+        return Ok(None);
+    }
+    if dry_run {
+        info!(
+            "Would {} a thumbnail for '{}' to '{}'",
+            verb,
+            thumb.file.to_string_lossy(),
+            to.to_string_lossy()
+        );
+    } else {
+        info!(
+            "{} a thumbnail for '{}' to '{}'",
+            verb_ing,
+            thumb.file.to_string_lossy(),
+            to.to_string_lossy()
+        );
+        let mut text = vec![("Thumb::URI", to_url.as_str())];
+        if let Some(mtime) = mtime {
+            text.push(("Thumb::MTime", mtime));
+        }
+        let result = rewrite_thumbnail(&thumb.thumbnail, &new_thumb, &text, copy);
+        let action = if copy {
+            JournalAction::Copy
+        } else {
+            JournalAction::Move
+        };
+        un.record(
+            action,
+            &thumb.thumbnail,
+            Some(&thumb.file),
+            Some(&new_thumb),
+            &result,
+        );
+        result?;
+    }
+
+    let app = thumb.app.clone();
+    Ok(Some(MovedThumbnail {
+        from: thumb,
+        to: Thumbnail {
+            thumbnail: new_thumb,
+            file: to.to_path_buf(),
+            app,
+        },
+    }))
+}
+
+/// Check if the thumbnail at `existing` is valid for the file at `file`: if its
+/// `Thumb::MTime` is the modification time of the file. If that can't be told,
+/// the thumbnail is assumed to be valid.
+fn is_up_to_date(existing: &Path, file: &Path) -> bool {
+    if !existing.exists() {
+        return false;
+    }
+    let mtime = File::open(existing)
+        .map_err(Into::into)
+        .and_then(|f| read_metadata(BufReader::new(f)))
+        .map(|m| m.mtime);
+    match (mtime, modified_secs(file)) {
+        (Ok(Some(mtime)), Ok(file_mtime)) => mtime == file_mtime,
+        (Ok(None), _) => false,
+        _ => true,
+    }
+}
+
+/// Write the thumbnail at `path` to `new_path` with the text chunks `text`, and
+/// delete the original unless `copy` is set.
+fn rewrite_thumbnail(
+    path: &Path,
+    new_path: &Path,
+    text: &[(&str, &str)],
+    copy: bool,
+) -> Result<()> {
+    let data = read(path)?;
+    let data = png::rewrite_text(&data, text, true)
+        .with_context(|| format!("Failed to rewrite {}", path.to_string_lossy()))?;
+    png::write_thumbnail(new_path, &data)?;
+    if !copy && new_path != path {
+        remove_file(path)
+            .with_context(|| format!("Failed to delete {}", path.to_string_lossy()))?;
+    }
+
+    Ok(())
+}
+
+fn cross_device(from: &Path, to: &Path) -> anyhow::Error {
+    anyhow!(
+        "Cannot move {} to {}, on another filesystem. Copy it there while keeping modification times (e.g. with 'cp -a'), then carry the thumbnails over with 'thumbs relocate'",
+        from.to_string_lossy(),
+        to.to_string_lossy()
+    )
+}
+
+/// Make `path` absolute the way `file_url` does: relative paths are resolved
+/// from the current directory, and absolute paths are left as is (without
+/// resolving symlinks). Paths that don't exist anymore are resolved without
+/// touching the filesystem.
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    if let Ok(path) = path.canonicalize() {
+        return Ok(path);
+    }
+
+    let mut absolute = std::env::current_dir()?;
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            c => absolute.push(c),
+        }
+    }
+
+    Ok(absolute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{thumbnail_path, thumbnail_uri, thumbnailed_file, TempDir};
+    use std::fs::{copy, create_dir_all};
+    use std::os::unix::fs::symlink;
+
+    /// A cache and a directory `from` with a thumbnailed file `x`.
+    fn setup(dir: &Path) -> (UnThumbnailer, PathBuf, PathBuf) {
+        let cache = dir.join("cache");
+        let from = dir.join("from");
+        create_dir_all(&from).unwrap();
+        thumbnailed_file(&cache, &from.join("x"));
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+
+        (un, cache, from)
+    }
+
+    #[test]
+    fn move_file_with_thumbnails() {
+        let dir = TempDir::new("relocate-mv");
+        let (un, cache, from) = setup(dir.path());
+        let (x, y) = (from.join("x"), from.join("y"));
+
+        let moved = un.move_file(&x, &y).unwrap();
+
+        assert_eq!(moved.len(), 1);
+        assert!(!x.exists() && y.exists());
+        assert!(!thumbnail_path(&cache, &x).exists());
+        let url = file_url(&y).unwrap();
+        assert_eq!(thumbnail_uri(&thumbnail_path(&cache, &y)), Some(url.into()));
+    }
+
+    #[test]
+    fn move_file_into_directory() {
+        let dir = TempDir::new("relocate-mv-dir");
+        let (un, cache, from) = setup(dir.path());
+        let to = dir.path().join("to");
+        create_dir_all(&to).unwrap();
+
+        un.move_file(&from.join("x"), &to).unwrap();
+
+        assert!(to.join("x").exists());
+        assert!(thumbnail_path(&cache, &to.join("x")).exists());
+    }
+
+    #[test]
+    fn relocate_moved_directory() {
+        let dir = TempDir::new("relocate-dir");
+        let (un, cache, from) = setup(dir.path());
+        let to = dir.path().join("to");
+        rename(&from, &to).unwrap();
+
+        // nothing changes on a dry run
+        assert_eq!(un.relocate(&from, &to, true).unwrap().len(), 1);
+        assert!(thumbnail_path(&cache, &from.join("x")).exists());
+
+        assert_eq!(un.relocate(&from, &to, false).unwrap().len(), 1);
+        assert!(!thumbnail_path(&cache, &from.join("x")).exists());
+        assert!(thumbnail_path(&cache, &to.join("x")).exists());
+    }
+
+    #[test]
+    fn relocate_ignores_copies() {
+        let dir = TempDir::new("relocate-copy");
+        let (un, cache, from) = setup(dir.path());
+        let to = dir.path().join("to");
+        create_dir_all(&to).unwrap();
+        copy(from.join("x"), to.join("x")).unwrap();
+
+        assert!(un.relocate(&from, &to, false).unwrap().is_empty());
+        assert!(thumbnail_path(&cache, &from.join("x")).exists());
+    }
+
+    #[test]
+    fn relocate_drops_superseded_thumbnails() {
+        let dir = TempDir::new("relocate-superseded");
+        let (un, cache, from) = setup(dir.path());
+        let to = dir.path().join("to");
+        rename(&from, &to).unwrap();
+        // already regenerated at the new location
+        let new_thumb = thumbnailed_file(&cache, &to.join("x"));
+        let before = read(&new_thumb).unwrap();
+
+        assert!(un.relocate(&from, &to, false).unwrap().is_empty());
+        assert!(!thumbnail_path(&cache, &from.join("x")).exists());
+        assert_eq!(read(&new_thumb).unwrap(), before);
+    }
+
+    #[test]
+    fn copy_thumbnails_to_copies() {
+        let dir = TempDir::new("relocate-cp");
+        let (un, cache, from) = setup(dir.path());
+        let to = dir.path().join("to");
+        create_dir_all(&to).unwrap();
+        copy(from.join("x"), to.join("x")).unwrap();
+
+        assert_eq!(un.copy_thumbnails(&from, &to).unwrap().len(), 1);
+        assert!(thumbnail_path(&cache, &from.join("x")).exists());
+        let url = file_url(&to.join("x")).unwrap();
+        let copied = thumbnail_path(&cache, &to.join("x"));
+        assert_eq!(thumbnail_uri(&copied), Some(url.into()));
+
+        // once is enough
+        assert!(un.copy_thumbnails(&from, &to).unwrap().is_empty());
+    }
+
+    #[test]
+    fn copy_thumbnails_of_same_content_only() {
+        let dir = TempDir::new("relocate-cp-differ");
+        let (un, cache, from) = setup(dir.path());
+        let to = dir.path().join("to");
+        create_dir_all(to.join("sub")).unwrap();
+        std::fs::write(to.join("x"), b"something else").unwrap();
+        // not copied
+        assert!(un.copy_thumbnails(&from, &to).unwrap().is_empty());
+        assert!(!thumbnail_path(&cache, &to.join("x")).exists());
+
+        // subdirectories are only copied when recursing
+        create_dir_all(from.join("sub")).unwrap();
+        thumbnailed_file(&cache, &from.join("sub/y"));
+        copy(from.join("sub/y"), to.join("sub/y")).unwrap();
+        assert!(un.copy_thumbnails(&from, &to).unwrap().is_empty());
+        let un = UnThumbnailer::with_cache_dir(true, false, false, &cache).unwrap();
+        assert_eq!(un.copy_thumbnails(&from, &to).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn absolute_paths() {
+        let dir = TempDir::new("relocate-absolute");
+        let link = dir.path().join("link");
+        symlink(dir.path(), &link).unwrap();
+
+        // symlinks of absolute paths are kept, like in `file_url`
+        assert_eq!(absolute_path(&link.join("x")).unwrap(), link.join("x"));
+        // relative paths that don't exist anymore
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            absolute_path(Path::new("./gone/sub/../x")).unwrap(),
+            cwd.join("gone/x")
+        );
+    }
+}
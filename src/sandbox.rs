@@ -0,0 +1,264 @@
+use crate::{find_cache_locations, Error, UnThumbnailer};
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A kind of application sandbox, with its own thumbnail caches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sandbox {
+    /// Caches in `~/.var/app/<id>/cache/thumbnails`
+    Flatpak,
+    /// Caches in `~/snap/<app>/<revision>/.cache/thumbnails`
+    Snap,
+}
+
+impl fmt::Display for Sandbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sandbox::Flatpak => write!(f, "flatpak"),
+            Sandbox::Snap => write!(f, "snap"),
+        }
+    }
+}
+
+/// The thumbnail cache of a sandboxed application.
+#[derive(Debug, Clone, Serialize)]
+pub struct AppCache {
+    pub app: String,
+    pub sandbox: Sandbox,
+    pub dir: PathBuf,
+}
+
+impl UnThumbnailer {
+    /// Also look for thumbnails in the caches of the Flatpak and Snap
+    /// applications of the user with the home directory `home`.
+    ///
+    /// If `apps` isn't empty, only the caches of the applications with these IDs
+    /// are used, instead of the main cache.
+    pub fn add_app_caches(&mut self, home: &Path, apps: &[String]) -> Result<(), Error> {
+        let mut caches = find_app_caches(home);
+        if !apps.is_empty() {
+            caches.retain(|c| apps.contains(&c.app));
+            for app in apps {
+                if !caches.iter().any(|c| &c.app == app) {
+                    warn!("Found no thumbnail cache for application '{}'", app);
+                }
+            }
+            self.cache_locs.clear();
+        }
+
+        for cache in &caches {
+            self.cache_locs.extend(find_cache_locations(&cache.dir)?);
+        }
+        self.app_caches.extend(caches);
+        self.apps = Some(apps.to_vec());
+
+        Ok(())
+    }
+
+    /// The caches of sandboxed applications that are used.
+    pub fn app_caches(&self) -> &[AppCache] {
+        &self.app_caches
+    }
+}
+
+fn find_app_caches(home: &Path) -> Vec<AppCache> {
+    let mut caches = Vec::new();
+
+    for entry in sub_dirs(&home.join(".var/app")) {
+        let dir = entry.join("cache/thumbnails");
+        if dir.is_dir() {
+            caches.push(AppCache {
+                app: entry.file_name().unwrap().to_string_lossy().into_owned(),
+                sandbox: Sandbox::Flatpak,
+                dir,
+            });
+        }
+    }
+
+    for app in sub_dirs(&home.join("snap")) {
+        // 'current' is a symlink to the current revision
+        for revision in sub_dirs(&app) {
+            let dir = revision.join(".cache/thumbnails");
+            if dir.is_dir() {
+                caches.push(AppCache {
+                    app: app.file_name().unwrap().to_string_lossy().into_owned(),
+                    sandbox: Sandbox::Snap,
+                    dir,
+                });
+            }
+        }
+    }
+
+    for cache in &caches {
+        debug!(
+            "Found a thumbnail cache for {} application '{}' in {}",
+            cache.sandbox,
+            cache.app,
+            cache.dir.to_string_lossy()
+        );
+    }
+
+    caches
+}
+
+/// The directories (but not symlinks to directories) in the directory at `path`.
+fn sub_dirs(path: &Path) -> Vec<PathBuf> {
+    WalkDir::new(path)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// The ID of the sandboxed application owning the thumbnail at `path`, if it
+/// is in the cache of one.
+pub(crate) fn thumbnail_app(path: &Path) -> Option<String> {
+    let components: Vec<&str> = path
+        .components()
+        .map(|c| match c {
+            Component::Normal(s) => s.to_str().unwrap_or(""),
+            _ => "",
+        })
+        .collect();
+    let i = components.iter().rposition(|c| *c == "thumbnails")?;
+    match components.get(i.checked_sub(4)?..i)? {
+        [".var", "app", app, "cache"] => Some(app.to_string()),
+        ["snap", app, _, ".cache"] => Some(app.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{thumbnail_path, thumbnailed_file, TempDir};
+    use globset::{Glob, GlobSet, GlobSetBuilder};
+    use std::fs::{create_dir_all, remove_file};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn app_of_thumbnails() {
+        assert_eq!(
+            thumbnail_app(Path::new(
+                "/home/me/.var/app/org.gnome.Nautilus/cache/thumbnails/normal/a.png"
+            )),
+            Some("org.gnome.Nautilus".to_string())
+        );
+        assert_eq!(
+            thumbnail_app(Path::new(
+                "/home/me/snap/firefox/2356/.cache/thumbnails/large/a.png"
+            )),
+            Some("firefox".to_string())
+        );
+        assert_eq!(
+            thumbnail_app(Path::new(
+                "/home/me/snap/firefox/2356/.cache/thumbnails/fail/gnome-thumbnail-factory/a.png"
+            )),
+            Some("firefox".to_string())
+        );
+        assert_eq!(
+            thumbnail_app(Path::new("/home/me/.cache/thumbnails/normal/a.png")),
+            None
+        );
+        assert_eq!(thumbnail_app(Path::new("/thumbnails/normal/a.png")), None);
+    }
+
+    #[test]
+    fn find_caches() {
+        let home = TempDir::new("sandbox");
+        let home = home.path();
+        create_dir_all(home.join(".var/app/org.gnome.Nautilus/cache/thumbnails")).unwrap();
+        // no thumbnail cache
+        create_dir_all(home.join(".var/app/org.gnome.Maps/cache")).unwrap();
+        create_dir_all(home.join("snap/firefox/2356/.cache/thumbnails")).unwrap();
+        symlink("2356", home.join("snap/firefox/current")).unwrap();
+
+        let caches = find_app_caches(home);
+        let found: Vec<_> = caches
+            .iter()
+            .map(|c| (c.app.as_str(), c.sandbox, c.dir.strip_prefix(home).unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "org.gnome.Nautilus",
+                    Sandbox::Flatpak,
+                    Path::new(".var/app/org.gnome.Nautilus/cache/thumbnails")
+                ),
+                (
+                    "firefox",
+                    Sandbox::Snap,
+                    Path::new("snap/firefox/2356/.cache/thumbnails")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn clean_up_app_caches() {
+        let home = TempDir::new("sandbox-cleanup");
+        let home = home.path();
+        let cache = home.join(".cache/thumbnails");
+        let app_cache = home.join(".var/app/org.gnome.Nautilus/cache/thumbnails");
+        let main_file = home.join("main.txt");
+        let app_file = home.join("app.txt");
+        thumbnailed_file(&cache, &main_file);
+        thumbnailed_file(&app_cache, &app_file);
+        remove_file(&main_file).unwrap();
+        remove_file(&app_file).unwrap();
+        let all = GlobSetBuilder::new()
+            .add(Glob::new("**").unwrap())
+            .build()
+            .unwrap();
+
+        // only the cache of the application
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        un.add_app_caches(home, &["org.gnome.Nautilus".into()])
+            .unwrap();
+        let stats = un.stats().unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].app.as_deref(), Some("org.gnome.Nautilus"));
+        assert_eq!(stats[0].thumbnails, 1);
+        let results = un.cleanup(true, false, &GlobSet::empty(), &all).unwrap();
+        assert_eq!(results.thumbnails.len(), 1);
+        assert_eq!(results.thumbnails[0].file, app_file);
+        assert_eq!(
+            results.thumbnails[0].app.as_deref(),
+            Some("org.gnome.Nautilus")
+        );
+        assert!(!thumbnail_path(&app_cache, &app_file).exists());
+        assert!(thumbnail_path(&cache, &main_file).exists());
+
+        // along with the main cache
+        thumbnailed_file(&app_cache, &app_file);
+        remove_file(&app_file).unwrap();
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        un.add_app_caches(home, &[]).unwrap();
+        assert_eq!(un.app_caches().len(), 1);
+        let mut counts: Vec<_> = un
+            .stats()
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.app, s.thumbnails))
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            [(None, 1), (Some("org.gnome.Nautilus".to_string()), 1)]
+        );
+        let results = un.cleanup(true, false, &GlobSet::empty(), &all).unwrap();
+        assert_eq!(results.thumbnails.len(), 2);
+        assert!(!thumbnail_path(&cache, &main_file).exists());
+        assert!(!thumbnail_path(&app_cache, &app_file).exists());
+    }
+}
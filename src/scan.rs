@@ -0,0 +1,595 @@
+//! Streaming access to the thumbnail cache.
+//!
+//! `UnThumbnailer::scan` and `UnThumbnailer::scan_files` find thumbnails one at a
+//! time, without reading them, and `UnThumbnailer::apply` runs an `Action` on the
+//! thumbnails found. The other operations of `UnThumbnailer` are built on them.
+
+use crate::journal::JournalAction;
+use crate::sandbox::thumbnail_app;
+use crate::{
+    file_url, is_hidden_unix, read_metadata, thumbnail_file_name, Error, Thumbnail,
+    ThumbnailMetadata, UnThumbnailer,
+};
+use anyhow::{anyhow, Result};
+use ignore::{Walk, WalkBuilder};
+use log::*;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{remove_file, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use url::Url;
+use walkdir::WalkDir;
+
+/// A thumbnail found in the cache.
+///
+/// The metadata of the thumbnail is only read when first needed.
+#[derive(Debug)]
+pub struct CacheEntry {
+    thumbnail: PathBuf,
+    app: Option<String>,
+    /// The file the thumbnail was found for, when scanning files.
+    file: Option<PathBuf>,
+    metadata: RefCell<Option<ThumbnailMetadata>>,
+}
+
+impl CacheEntry {
+    pub(crate) fn new(thumbnail: PathBuf, file: Option<PathBuf>) -> Self {
+        CacheEntry {
+            app: thumbnail_app(&thumbnail),
+            thumbnail,
+            file,
+            metadata: RefCell::new(None),
+        }
+    }
+
+    /// The path of the thumbnail.
+    pub fn thumbnail(&self) -> &Path {
+        &self.thumbnail
+    }
+
+    /// The ID of the sandboxed application whose cache contains the thumbnail.
+    pub fn app(&self) -> Option<&str> {
+        self.app.as_deref()
+    }
+
+    /// The `Thumb::URI` of the thumbnail: the URI of its original file.
+    pub fn uri(&self) -> Result<Option<String>, Error> {
+        Ok(self.metadata()?.uri)
+    }
+
+    /// The `Thumb::MTime` of the thumbnail: the modification time of its
+    /// original file when the thumbnail was created, in seconds.
+    pub fn mtime(&self) -> Result<Option<u64>, Error> {
+        Ok(self.metadata()?.mtime)
+    }
+
+    /// The original file of the thumbnail: the file it was found for when
+    /// scanning files, or the file its `Thumb::URI` points to otherwise.
+    ///
+    /// Returns `None` for thumbnails without a URI, or with a URI that isn't a
+    /// local file.
+    pub fn file(&self) -> Result<Option<PathBuf>, Error> {
+        if let Some(file) = &self.file {
+            return Ok(Some(file.clone()));
+        }
+
+        let uri = match self.uri()? {
+            Some(uri) => uri,
+            None => return Ok(None),
+        };
+        let url = Url::parse(&uri)?;
+        if url.scheme() != "file" {
+            return Ok(None);
+        }
+
+        Ok(url.to_file_path().ok())
+    }
+
+    /// The thumbnail and its original file, if it has one.
+    pub fn to_thumbnail(&self) -> Result<Option<Thumbnail>, Error> {
+        Ok(self.file()?.map(|file| Thumbnail {
+            thumbnail: self.thumbnail.clone(),
+            file,
+            app: self.app.clone(),
+        }))
+    }
+
+    fn metadata(&self) -> Result<ThumbnailMetadata> {
+        if let Some(meta) = &*self.metadata.borrow() {
+            return Ok(meta.clone());
+        }
+
+        let meta = read_metadata(BufReader::new(File::open(&self.thumbnail)?))?;
+        *self.metadata.borrow_mut() = Some(meta.clone());
+
+        Ok(meta)
+    }
+}
+
+/// The thumbnails of the whole cache, returned by `UnThumbnailer::scan`.
+pub struct Scan<'a> {
+    entries: Box<dyn Iterator<Item = Result<CacheEntry, Error>> + 'a>,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<CacheEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+/// The thumbnails of some files, returned by `UnThumbnailer::scan_files`.
+pub struct FileScan<'a> {
+    un: &'a UnThumbnailer,
+    paths: std::slice::Iter<'a, PathBuf>,
+    /// The walk of the current directory.
+    walk: Option<Walk>,
+    last_accessed: Option<SystemTime>,
+    /// The file thumbnails are being returned for.
+    file: Option<PathBuf>,
+    /// Thumbnails found for the current file, not returned yet.
+    found: VecDeque<CacheEntry>,
+    progress: Progress,
+    ignored_directories: u32,
+    interrupted: bool,
+}
+
+impl FileScan<'_> {
+    /// The number of directories skipped so far because recursing is disabled.
+    pub fn ignored_directories(&self) -> u32 {
+        self.ignored_directories
+    }
+
+    /// Whether the scan was stopped early by the callback of `cancel_when`.
+    pub fn interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    /// The file of the last thumbnail or error returned. Iterating can go on
+    /// after an error, with the next file.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    fn finish(&mut self) {
+        if !self.progress.finished {
+            self.progress.finished = true;
+            self.un.report_progress(self.progress);
+        }
+    }
+
+    /// The next file to find thumbnails for.
+    fn next_file(&mut self) -> Option<PathBuf> {
+        loop {
+            let walk = match &mut self.walk {
+                Some(walk) => walk,
+                None => {
+                    let path = self.paths.next()?;
+                    if path.is_file() {
+                        return Some(path.clone());
+                    }
+                    self.walk = Some(self.un.walk_builder(path).build());
+                    continue;
+                }
+            };
+
+            let entry = match walk.next() {
+                Some(Ok(e)) => e,
+                Some(Err(_)) => continue,
+                None => {
+                    self.walk = None;
+                    continue;
+                }
+            };
+            if entry.depth() == 0 {
+                continue;
+            }
+            trace!("entry: {:?}", entry);
+            if entry.file_type().is_some_and(|t| t.is_dir()) {
+                if !self.un.recursive {
+                    self.ignored_directories += 1;
+                }
+                continue;
+            }
+            if let Some(last_accessed) = self.last_accessed {
+                match entry
+                    .metadata()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|m| m.accessed().map_err(Into::into))
+                {
+                    Ok(t) if t >= last_accessed => continue,
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!(
+                            "Failed to find accesstime of {}",
+                            entry.path().to_string_lossy()
+                        );
+                        trace!("Failed with {}", e);
+                        continue;
+                    }
+                }
+            }
+
+            return Some(entry.into_path());
+        }
+    }
+}
+
+impl Iterator for FileScan<'_> {
+    type Item = Result<CacheEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.found.is_empty() {
+            if self.interrupted || self.un.cancelled() {
+                self.interrupted = true;
+                self.finish();
+                return None;
+            }
+            let file = match self.next_file() {
+                Some(f) => f,
+                None => {
+                    self.finish();
+                    return None;
+                }
+            };
+            let found = self.un.thumbnails_of(&file);
+            self.file = Some(file);
+            self.progress.done += 1;
+            if let Ok(found) = &found {
+                self.progress.found += found.len() as u64;
+            }
+            self.un.report_progress(self.progress);
+            match found {
+                Ok(found) => self.found.extend(found),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+
+        self.found.pop_front().map(Ok)
+    }
+}
+
+impl Drop for FileScan<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// What to do with a thumbnail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Only report the thumbnail, without changing anything.
+    Report,
+    /// Delete the thumbnail.
+    Delete,
+    /// Move the thumbnail to quarantine, to be restored with `undo`. Requires
+    /// `enable_quarantine`.
+    Quarantine,
+}
+
+/// The progress of an operation on many thumbnails or files.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Progress {
+    /// The number of items scanned.
+    pub done: u64,
+    /// The total number of items to scan, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// The number of thumbnails found to act on.
+    pub found: u64,
+    /// Whether the operation is over. This is the last report.
+    pub finished: bool,
+}
+
+/// A callback to report progress to.
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
+
+/// A callback telling if the current operation should stop.
+#[derive(Clone)]
+pub(crate) struct CancelCallback(Arc<dyn Fn() -> bool + Send + Sync>);
+
+impl fmt::Debug for CancelCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CancelCallback")
+    }
+}
+
+impl UnThumbnailer {
+    /// Iterate over all the thumbnails in the cache.
+    ///
+    /// Thumbnails are always returned in the same order: by cache directory, then
+    /// by name. Errors reading a directory of the cache are returned, and
+    /// iterating can go on after them.
+    pub fn scan(&self) -> Scan<'_> {
+        self.scan_after(None)
+    }
+
+    /// Same as `scan`, but only returns the thumbnails after the thumbnail at
+    /// `after`, to resume an interrupted scan. The thumbnail doesn't have to
+    /// exist anymore.
+    pub fn scan_after(&self, after: Option<&Path>) -> Scan<'_> {
+        self.walk_cache(after, false)
+    }
+
+    /// Same as `scan`, but returns every file of the cache, including hidden and
+    /// non-PNG files (like the leftovers `fsck` looks for).
+    pub(crate) fn scan_all(&self) -> Scan<'_> {
+        self.walk_cache(None, true)
+    }
+
+    fn walk_cache(&self, after: Option<&Path>, all: bool) -> Scan<'_> {
+        let resume = after.and_then(|a| {
+            let location = self
+                .cache_locs
+                .iter()
+                .position(|l| Some(l.as_path()) == a.parent());
+            if location.is_none() {
+                debug!(
+                    "{} isn't in the cache, scanning from the start",
+                    a.to_string_lossy()
+                );
+            }
+            Some((location?, a.file_name()?.to_os_string()))
+        });
+        let start = resume.as_ref().map_or(0, |(i, _)| *i);
+
+        let hidden = self.hidden || all;
+        let entries = self
+            .cache_locs
+            .iter()
+            .enumerate()
+            .skip(start)
+            .flat_map(move |(i, location)| {
+                let after = match &resume {
+                    Some((start, name)) if *start == i => Some(name.clone()),
+                    _ => None,
+                };
+                WalkDir::new(location)
+                    .min_depth(1)
+                    .max_depth(1)
+                    .sort_by_file_name()
+                    .into_iter()
+                    .filter_entry(move |e| hidden || !is_hidden_unix(e.file_name()))
+                    .filter(move |e| match (e, &after) {
+                        (Ok(e), Some(after)) => e.file_name() > after.as_os_str(),
+                        _ => true,
+                    })
+            })
+            .filter(move |e| match e {
+                Ok(e) => {
+                    !e.file_type().is_dir()
+                        && (all || e.path().extension().is_some_and(|p| p == "png"))
+                }
+                Err(_) => true,
+            })
+            .map(|e| {
+                e.map(|e| CacheEntry::new(e.into_path(), None))
+                    .map_err(Into::into)
+            });
+
+        Scan {
+            entries: Box::new(entries),
+        }
+    }
+
+    /// Iterate over the thumbnails of the files at `paths`, possibly recursing
+    /// in directories if enabled. Progress is reported for each file.
+    ///
+    /// Files last accessed after `last_accessed` are skipped, as well as files
+    /// ignored by `.gitignore`, `.ignore` or `.thumbsignore` files if
+    /// `respect_ignore` is set.
+    pub fn scan_files<'a>(
+        &'a self,
+        paths: &'a [PathBuf],
+        last_accessed: Option<SystemTime>,
+    ) -> FileScan<'a> {
+        FileScan {
+            un: self,
+            paths: paths.iter(),
+            walk: None,
+            last_accessed,
+            file: None,
+            found: VecDeque::new(),
+            progress: Progress::default(),
+            ignored_directories: 0,
+            interrupted: false,
+        }
+    }
+
+    /// The action that deletes thumbnails: `Quarantine` if it is enabled,
+    /// `Delete` otherwise.
+    pub fn delete_action(&self) -> Action {
+        if self.quarantine.is_some() {
+            Action::Quarantine
+        } else {
+            Action::Delete
+        }
+    }
+
+    /// Apply `action` to the thumbnail `thumb`. Changes are recorded in the
+    /// journal if it is enabled.
+    pub fn apply(&self, action: Action, thumb: &Thumbnail) -> Result<(), Error> {
+        Ok(self.run_action(action, thumb)?)
+    }
+
+    /// Call `on_progress` with the progress of `delete`, `cleanup` and `stats`.
+    pub fn on_progress<F>(&mut self, on_progress: F)
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(ProgressCallback(Arc::new(on_progress)));
+    }
+
+    pub(crate) fn run_action(&self, action: Action, thumb: &Thumbnail) -> Result<()> {
+        let (action, result) = match action {
+            Action::Report => return Ok(()),
+            Action::Delete => (
+                JournalAction::Delete,
+                remove_file(&thumb.thumbnail).map_err(Into::into),
+            ),
+            Action::Quarantine => (
+                JournalAction::Quarantine,
+                match &self.quarantine {
+                    Some(q) => q.add(thumb),
+                    None => Err(anyhow!("Quarantine is not enabled")),
+                },
+            ),
+        };
+        self.record(action, &thumb.thumbnail, Some(&thumb.file), None, &result);
+
+        result
+    }
+
+    /// Stop `delete` and `cleanup` after the current thumbnail when `cancelled`
+    /// returns true. The results of the thumbnails done are still returned, and
+    /// marked as interrupted.
+    pub fn cancel_when<F>(&mut self, cancelled: F)
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.cancel = Some(CancelCallback(Arc::new(cancelled)));
+    }
+
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|CancelCallback(c)| c())
+    }
+
+    /// Whether progress is reported, in which case counting the items to
+    /// process beforehand is worth it.
+    pub(crate) fn wants_progress(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    pub(crate) fn report_progress(&self, progress: Progress) {
+        if let Some(ProgressCallback(callback)) = &self.progress {
+            callback(&progress);
+        }
+    }
+
+    /// The thumbnails of the file at `path`, in all the cache directories.
+    pub(crate) fn thumbnails_of(&self, path: &Path) -> Result<Vec<CacheEntry>> {
+        let url = file_url(path)?;
+        trace!("Url: {:?}", url);
+
+        let name = thumbnail_file_name(&url);
+
+        debug!("Processing {:?} ({})", path, name);
+
+        let mut found = Vec::new();
+        for location in &self.cache_locs {
+            let thumb = location.join(&name);
+            if thumb.exists() {
+                debug!("  Found      {:?}", thumb);
+                found.push(CacheEntry::new(thumb, Some(path.to_path_buf())));
+            } else {
+                debug!("  Not found  {:?}", thumb);
+            }
+        }
+
+        if found.is_empty() {
+            debug!(
+                "Could not find a thumbnail for '{}'",
+                path.to_string_lossy()
+            );
+        }
+
+        Ok(found)
+    }
+
+    fn walk_builder(&self, path: &Path) -> WalkBuilder {
+        let mut walk = WalkBuilder::new(path);
+        walk.standard_filters(false).hidden(!self.hidden);
+        if !self.recursive {
+            walk.max_depth(Some(1));
+        }
+        if self.respect_ignore {
+            // the ignore files of the parent directories apply too
+            walk.parents(true)
+                .git_ignore(true)
+                .git_exclude(true)
+                .ignore(true)
+                .require_git(false)
+                .add_custom_ignore_filename(".thumbsignore");
+        }
+
+        walk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{thumbnailed_file, TempDir};
+    use std::fs::{copy, create_dir_all, remove_file, write};
+
+    fn thumbnails(scan: Scan) -> Vec<PathBuf> {
+        scan.map(|e| e.unwrap().thumbnail().to_path_buf()).collect()
+    }
+
+    /// A cache with thumbnails in `normal` and `large`, and the thumbnails in
+    /// scanning order.
+    fn cache(dir: &Path) -> (UnThumbnailer, Vec<PathBuf>) {
+        let cache = dir.join("cache");
+        let mut normal: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|f| thumbnailed_file(&cache, &dir.join(f)))
+            .collect();
+        normal.sort();
+        let large = cache.join("large").join(normal[0].file_name().unwrap());
+        create_dir_all(large.parent().unwrap()).unwrap();
+        copy(&normal[0], &large).unwrap();
+        // not thumbnails
+        write(cache.join("normal/.hidden.png"), b"").unwrap();
+        write(cache.join("normal/notes.txt"), b"").unwrap();
+
+        let un = UnThumbnailer::with_cache_dir(false, false, false, &cache).unwrap();
+        normal.push(large);
+        (un, normal)
+    }
+
+    #[test]
+    fn scan_in_order() {
+        let dir = TempDir::new("scan-order");
+        let (un, all) = cache(dir.path());
+
+        assert_eq!(thumbnails(un.scan()), all);
+        assert_eq!(thumbnails(un.scan_after(None)), all);
+    }
+
+    #[test]
+    fn resume_after_thumbnail() {
+        let dir = TempDir::new("scan-resume");
+        let (un, all) = cache(dir.path());
+
+        assert_eq!(thumbnails(un.scan_after(Some(&all[0]))), &all[1..]);
+        // the next cache directory
+        assert_eq!(thumbnails(un.scan_after(Some(&all[2]))), &all[3..]);
+        assert!(thumbnails(un.scan_after(Some(&all[3]))).is_empty());
+
+        // deleted since
+        remove_file(&all[1]).unwrap();
+        assert_eq!(thumbnails(un.scan_after(Some(&all[1]))), &all[2..]);
+    }
+
+    #[test]
+    fn resume_after_unknown_thumbnail() {
+        let dir = TempDir::new("scan-unknown");
+        let (un, all) = cache(dir.path());
+
+        let outside = dir.path().join("elsewhere/a.png");
+        assert_eq!(thumbnails(un.scan_after(Some(&outside))), all);
+    }
+}
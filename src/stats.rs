@@ -0,0 +1,66 @@
+use crate::sandbox::thumbnail_app;
+use crate::{Error, Progress, UnThumbnailer};
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+use std::fs::metadata;
+use std::path::PathBuf;
+
+/// Statistics about a directory of the thumbnail cache.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    /// The directory, like `~/.cache/thumbnails/normal`.
+    pub dir: PathBuf,
+    /// The ID of the sandboxed application owning the directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    /// The number of thumbnails in the directory.
+    pub thumbnails: u64,
+    /// The total size of the thumbnails, in bytes.
+    pub size: u64,
+}
+
+impl UnThumbnailer {
+    /// Count the thumbnails in each directory of the cache, and their size.
+    ///
+    /// Progress is reported for each thumbnail counted.
+    pub fn stats(&self) -> Result<Vec<CacheStats>, Error> {
+        let mut stats: Vec<_> = self
+            .cache_locs
+            .iter()
+            .map(|location| CacheStats {
+                dir: location.clone(),
+                app: thumbnail_app(location),
+                thumbnails: 0,
+                size: 0,
+            })
+            .collect();
+        let mut progress = Progress::default();
+        for entry in self.scan().filter_map(|e| e.ok()) {
+            let s = match stats
+                .iter_mut()
+                .find(|s| Some(s.dir.as_path()) == entry.thumbnail().parent())
+            {
+                Some(s) => s,
+                None => continue,
+            };
+            // the thumbnail may have been deleted since
+            let size = match metadata(entry.thumbnail()) {
+                Ok(m) => m.len(),
+                Err(e) => {
+                    debug!("{} for {}", e, entry.thumbnail().to_string_lossy());
+                    continue;
+                }
+            };
+            s.thumbnails += 1;
+            s.size += size;
+            progress.done += 1;
+            progress.found += 1;
+            self.report_progress(progress);
+        }
+        progress.finished = true;
+        self.report_progress(progress);
+
+        Ok(stats)
+    }
+}
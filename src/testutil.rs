@@ -0,0 +1,85 @@
+//! Helpers for the unit tests.
+
+use crate::journal::{Journal, JournalAction};
+use crate::png::{self, Image};
+use crate::{file_url, read_metadata, thumbnail_file_name, UnThumbnailer};
+use std::fs::{create_dir_all, metadata, read, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// A temporary directory, removed when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("thumbs-test-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        TempDir(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// Record the changes made by `un` in a journal in the directory `dir`.
+pub(crate) fn enable_journal(un: &mut UnThumbnailer, dir: &Path) {
+    un.journal = Some(Journal::at(dir.join("journal.jsonl")));
+}
+
+/// The actions recorded in the journal of `un`, with their results.
+pub(crate) fn journal_actions(un: &UnThumbnailer) -> Vec<(JournalAction, String)> {
+    un.history(None, None, None)
+        .unwrap()
+        .into_iter()
+        .map(|e| (e.action, e.result))
+        .collect()
+}
+
+/// Create the file at `path` and its thumbnail in the `normal` directory of the
+/// cache `cache`. Returns the path of the thumbnail.
+pub(crate) fn thumbnailed_file(cache: &Path, path: &Path) -> PathBuf {
+    write(path, path.to_string_lossy().as_bytes()).unwrap();
+    let mtime = metadata(path)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_string();
+    let url = file_url(path).unwrap();
+
+    let image = Image::with_color(8, 8, [0, 0, 0, 255]).encode().unwrap();
+    let data = png::rewrite_text(
+        &image,
+        &[("Thumb::URI", url.as_str()), ("Thumb::MTime", &mtime)],
+        false,
+    )
+    .unwrap();
+    let thumb = thumbnail_path(cache, path);
+    create_dir_all(thumb.parent().unwrap()).unwrap();
+    write(&thumb, data).unwrap();
+
+    thumb
+}
+
+/// The path of the thumbnail of the file at `path` in the `normal` directory of
+/// the cache `cache`.
+pub(crate) fn thumbnail_path(cache: &Path, path: &Path) -> PathBuf {
+    let url = file_url(path).unwrap();
+    cache.join("normal").join(thumbnail_file_name(&url))
+}
+
+/// The `Thumb::URI` of the thumbnail at `thumb`.
+pub(crate) fn thumbnail_uri(thumb: &Path) -> Option<String> {
+    read_metadata(read(thumb).unwrap().as_slice()).unwrap().uri
+}
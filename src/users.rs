@@ -0,0 +1,332 @@
+use crate::quarantine::Quarantine;
+use crate::{CleanupResults, Error, UnThumbnailer};
+use anyhow::{anyhow, Context, Result};
+use globset::GlobSet;
+use log::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::read_to_string;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const PASSWD: &str = "/etc/passwd";
+
+/// A user account, whose thumbnail cache is in its home directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+impl User {
+    /// The users from `/etc/passwd` that have an existing home directory.
+    pub fn all() -> Result<Vec<User>, Error> {
+        let mut homes = HashSet::new();
+        Ok(read_passwd()?
+            .into_iter()
+            .filter(|u| u.home != Path::new("/") && u.home.is_dir())
+            .filter(|u| homes.insert(u.home.clone()))
+            .collect())
+    }
+
+    /// The user owning the home directory at `home`.
+    pub fn from_home(home: &Path) -> Result<User, Error> {
+        let meta = home
+            .metadata()
+            .with_context(|| format!("Failed to read {}", home.to_string_lossy()))?;
+        if !meta.is_dir() {
+            return Err(anyhow!("{} is not a directory", home.to_string_lossy()).into());
+        }
+
+        let name = read_passwd()?
+            .into_iter()
+            .find(|u| u.uid == meta.uid())
+            .map_or_else(|| meta.uid().to_string(), |u| u.name);
+        Ok(User {
+            name,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            home: home.to_path_buf(),
+        })
+    }
+
+    /// The default thumbnail cache of the user.
+    pub fn cache_dir(&self) -> PathBuf {
+        self.home.join(".cache/thumbnails")
+    }
+}
+
+/// Results of cleaning up the thumbnail cache of `user`.
+#[derive(Debug, Serialize)]
+pub struct UserCleanupResults {
+    pub user: User,
+    #[serde(flatten)]
+    pub results: CleanupResults,
+}
+
+impl UnThumbnailer {
+    /// Same as `cleanup`, but for the thumbnail cache of each user of `users`
+    /// instead of the cache of this `UnThumbnailer`. Requires root privileges.
+    ///
+    /// Each cache is cleaned up with the identity of its user, so that files are
+    /// checked with the permissions (and mounts) of that user, and so that the
+    /// ownership of the cache is preserved. The legacy caches and the caches of
+    /// sandboxed applications are included if `add_legacy_cache` and
+    /// `add_app_caches` were used. Users without a thumbnail cache
+    /// are skipped.
+    pub fn cleanup_users(
+        &self,
+        users: &[User],
+        force: bool,
+        rename: bool,
+        exclude: &GlobSet,
+        include: &GlobSet,
+    ) -> Result<Vec<UserCleanupResults>, Error> {
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(
+                anyhow!("Cleaning up the caches of other users requires root privileges").into(),
+            );
+        }
+
+        let mut all_results = Vec::new();
+        for user in users {
+            if self.cancelled() {
+                break;
+            }
+            let results = {
+                let _guard = AsUser::switch(user)?;
+                self.user_cleanup(user, force, rename, exclude, include)
+            };
+            match results {
+                Ok(Some(results)) => all_results.push(UserCleanupResults {
+                    user: user.clone(),
+                    results,
+                }),
+                Ok(None) => debug!("User '{}' has no thumbnail cache", user.name),
+                Err(e) => warn!(
+                    "Failed to clean up thumbnails of user '{}': {:#}",
+                    user.name, e
+                ),
+            }
+        }
+
+        Ok(all_results)
+    }
+
+    fn user_cleanup(
+        &self,
+        user: &User,
+        force: bool,
+        rename: bool,
+        exclude: &GlobSet,
+        include: &GlobSet,
+    ) -> Result<Option<CleanupResults>> {
+        let mut un = UnThumbnailer::with_cache_dir(
+            self.recursive,
+            self.hidden,
+            self.respect_ignore,
+            &user.cache_dir(),
+        )?;
+        if self.legacy {
+            un.add_legacy_cache(&user.home)?;
+        }
+        if let Some(apps) = &self.apps {
+            un.add_app_caches(&user.home, apps)?;
+        }
+        if self.quarantine.is_some() {
+            un.quarantine = Some(Quarantine::for_user(&user.home));
+        }
+        if let Some(journal) = &self.journal {
+            un.journal = Some(journal.for_user(&user.home));
+        }
+        un.progress = self.progress.clone();
+        un.cancel = self.cancel.clone();
+        if un.cache_locs.is_empty() {
+            return Ok(None);
+        }
+
+        info!("Cleaning up thumbnails of user '{}'", user.name);
+        Ok(Some(un.cleanup(force, rename, exclude, include)?))
+    }
+}
+
+fn read_passwd() -> Result<Vec<User>> {
+    let content = read_to_string(PASSWD).with_context(|| format!("Failed to read {}", PASSWD))?;
+    Ok(parse_passwd(&content))
+}
+
+/// The users of the `/etc/passwd` file with the content `content`.
+fn parse_passwd(content: &str) -> Vec<User> {
+    let mut users = Vec::new();
+    for line in content.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 7 {
+            debug!("Ignoring invalid line in {}: {}", PASSWD, line);
+            continue;
+        }
+        match (fields[2].parse(), fields[3].parse()) {
+            (Ok(uid), Ok(gid)) => users.push(User {
+                name: fields[0].to_string(),
+                uid,
+                gid,
+                home: PathBuf::from(fields[5]),
+            }),
+            _ => debug!("Ignoring invalid line in {}: {}", PASSWD, line),
+        }
+    }
+
+    users
+}
+
+/// Runs as another user (by changing the effective user and groups of the
+/// process) until dropped.
+struct AsUser {
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+impl AsUser {
+    fn switch(user: &User) -> Result<AsUser> {
+        let groups = unsafe {
+            let n = libc::getgroups(0, std::ptr::null_mut());
+            if n < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let mut groups = vec![0; n as usize];
+            let n = libc::getgroups(n, groups.as_mut_ptr());
+            if n < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            groups.truncate(n as usize);
+            groups
+        };
+
+        // groups can only be changed while still root
+        let name = CString::new(user.name.as_str())?;
+        check(unsafe { libc::initgroups(name.as_ptr(), user.gid as _) })
+            .or_else(|_| check(unsafe { libc::setgroups(1, &user.gid) }))
+            .context("Failed to change groups")?;
+        let guard = AsUser {
+            gid: unsafe { libc::getegid() },
+            groups,
+        };
+        check(unsafe { libc::setegid(user.gid) }).context("Failed to change group")?;
+        check(unsafe { libc::seteuid(user.uid) }).context("Failed to change user")?;
+
+        Ok(guard)
+    }
+}
+
+impl Drop for AsUser {
+    fn drop(&mut self) {
+        let restored = check(unsafe { libc::seteuid(0) })
+            .and_then(|_| check(unsafe { libc::setegid(self.gid) }))
+            .and_then(|_| {
+                check(unsafe { libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) })
+            });
+        if let Err(e) = restored {
+            // carrying on as the wrong user would be much worse
+            error!("Failed to switch back to root: {}", e);
+            std::process::abort();
+        }
+    }
+}
+
+fn check(ret: std::os::raw::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::testutil::{thumbnail_path, thumbnailed_file, TempDir};
+    use globset::{Glob, GlobSetBuilder};
+    use std::fs::{create_dir_all, remove_file};
+
+    #[test]
+    fn parse_users() {
+        let users = parse_passwd(
+            "root:x:0:0:root:/root:/bin/bash\n\
+             # comment\n\
+             invalid:x:1000\n\
+             me:x:1000:100:Me:/home/me:/bin/sh\n\
+             bad:x:uid:100::/home/bad:/bin/sh\n",
+        );
+
+        let names: Vec<_> = users
+            .iter()
+            .map(|u| (u.name.as_str(), u.uid, u.gid))
+            .collect();
+        assert_eq!(names, [("root", 0, 0), ("me", 1000, 100)]);
+        assert_eq!(users[1].home, Path::new("/home/me"));
+        assert_eq!(
+            users[1].cache_dir(),
+            Path::new("/home/me/.cache/thumbnails")
+        );
+    }
+
+    #[test]
+    fn user_from_home() {
+        let dir = TempDir::new("users-home");
+        let user = User::from_home(dir.path()).unwrap();
+
+        assert_eq!(user.uid, unsafe { libc::geteuid() });
+        assert!(User::from_home(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn clean_up_user_cache() {
+        let dir = TempDir::new("users-cleanup");
+        let user = User::from_home(dir.path()).unwrap();
+        let cache = user.cache_dir();
+        let missing = dir.path().join("missing");
+        thumbnailed_file(&cache, &missing);
+        remove_file(&missing).unwrap();
+        let own_cache = dir.path().join("own-cache");
+        create_dir_all(&own_cache).unwrap();
+        let mut un = UnThumbnailer::with_cache_dir(false, false, false, &own_cache).unwrap();
+        un.quarantine = Some(Quarantine::new(dir.path().join("own-quarantine")));
+        un.journal = Some(Journal::at(dir.path().join("own-journal.jsonl")));
+        let all = GlobSetBuilder::new()
+            .add(Glob::new("**").unwrap())
+            .build()
+            .unwrap();
+
+        let results = un
+            .user_cleanup(&user, true, false, &GlobSet::empty(), &all)
+            .unwrap()
+            .unwrap();
+        assert_eq!(results.thumbnails.len(), 1);
+        assert!(!thumbnail_path(&cache, &missing).exists());
+        // in the quarantine and journal of the user
+        assert!(dir.path().join(".local/share/thumbs/quarantine").is_dir());
+        assert!(dir
+            .path()
+            .join(".local/state/thumbs/journal.jsonl")
+            .is_file());
+        assert!(!dir.path().join("own-quarantine").exists());
+        assert!(!dir.path().join("own-journal.jsonl").exists());
+
+        // users without a cache are skipped
+        let other = User {
+            home: dir.path().join("other"),
+            ..user
+        };
+        assert!(un
+            .user_cleanup(&other, true, false, &GlobSet::empty(), &all)
+            .unwrap()
+            .is_none());
+    }
+}
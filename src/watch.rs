@@ -0,0 +1,355 @@
+use crate::{
+    delete_thumbnail, is_hidden_unix, Action, Error, MovedThumbnail, Thumbnail, UnThumbnailer,
+};
+use anyhow::{Context, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A change made to the thumbnail cache while watching directories.
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// The thumbnail was deleted.
+    Deleted(Thumbnail),
+    /// The thumbnail was carried over to the new location of its file.
+    Moved(MovedThumbnail),
+}
+
+enum Change {
+    Removed(PathBuf),
+    RemovedDir(PathBuf),
+    Moved(PathBuf, PathBuf),
+    NewDir(PathBuf),
+    MovedDir(PathBuf, PathBuf),
+}
+
+impl UnThumbnailer {
+    /// Watch the directories at `paths` and delete the thumbnails of files as soon
+    /// as they are deleted, moved away, written to or replaced by a moved file,
+    /// possibly recursing in directories if enabled. `dry_run` only reports results
+    /// but doesn't actually delete anything.
+    ///
+    /// Thumbnails of files (or directories) moved inside the watched directories
+    /// are carried over to their new location instead of being deleted.
+    ///
+    /// `on_event` is called for every change to the thumbnail cache. This only
+    /// returns if watching fails.
+    pub fn watch<F>(&self, paths: &[PathBuf], dry_run: bool, mut on_event: F) -> Result<(), Error>
+    where
+        F: FnMut(&WatchEvent),
+    {
+        let action = if dry_run {
+            Action::Report
+        } else {
+            self.delete_action()
+        };
+        let mut watcher = Watcher {
+            inotify: Inotify::init().context("Failed to initialize inotify")?,
+            dirs: HashMap::new(),
+            recursive: self.recursive,
+            hidden: self.hidden,
+        };
+
+        for path in paths {
+            watcher.add(&path.canonicalize()?)?;
+        }
+
+        let mut buffer = [0; 4096];
+        loop {
+            let events = watcher
+                .inotify
+                .read_events_blocking(&mut buffer)
+                .context("Failed to read inotify events")?;
+
+            let mut changed = Vec::new();
+            // moves are reported as a pair of events with the same cookie
+            let mut moved_from = HashMap::new();
+            for event in events {
+                trace!("event: {:?}", event);
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    warn!("Too many changes, some were missed. Run 'cleanup' to catch up.");
+                    continue;
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    watcher.dirs.remove(&event.wd);
+                    continue;
+                }
+
+                let (dir, name) = match (watcher.dirs.get(&event.wd), event.name) {
+                    (Some(dir), Some(name)) => (dir, name),
+                    _ => continue,
+                };
+                if !self.hidden && is_hidden_unix(name) {
+                    continue;
+                }
+                let path = dir.join(name);
+
+                let is_dir = event.mask.contains(EventMask::ISDIR);
+                if event.mask.contains(EventMask::MOVED_FROM) {
+                    moved_from.insert(event.cookie, (path, is_dir, changed.len()));
+                    changed.push(None);
+                } else if event.mask.contains(EventMask::MOVED_TO) {
+                    match moved_from.remove(&event.cookie) {
+                        Some((from, _, i)) if is_dir => {
+                            changed[i] = Some(Change::MovedDir(from, path))
+                        }
+                        Some((from, _, i)) => changed[i] = Some(Change::Moved(from, path)),
+                        None if is_dir => changed.push(Some(Change::NewDir(path))),
+                        // moved from elsewhere, or from a skipped hidden file (like
+                        // the temporary file of an editor saving atomically): the
+                        // thumbnails of the file it replaces are stale
+                        None => changed.push(Some(Change::Removed(path))),
+                    }
+                } else if is_dir {
+                    if event.mask.contains(EventMask::CREATE) {
+                        changed.push(Some(Change::NewDir(path)));
+                    } else if event.mask.contains(EventMask::DELETE) {
+                        changed.push(Some(Change::RemovedDir(path)));
+                    }
+                } else if event
+                    .mask
+                    .intersects(EventMask::DELETE | EventMask::CLOSE_WRITE)
+                {
+                    changed.push(Some(Change::Removed(path)));
+                }
+            }
+            // files moved out of the watched directories
+            for (from, is_dir, i) in moved_from.into_values() {
+                changed[i] = Some(if is_dir {
+                    Change::RemovedDir(from)
+                } else {
+                    Change::Removed(from)
+                });
+            }
+
+            for change in changed.into_iter().flatten() {
+                if let Err(e) = self.handle_change(&change, &mut watcher, action, &mut on_event) {
+                    warn!("{:#}", e);
+                }
+            }
+        }
+    }
+
+    fn handle_change<F>(
+        &self,
+        change: &Change,
+        watcher: &mut Watcher,
+        action: Action,
+        on_event: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&WatchEvent),
+    {
+        let dry_run = action == Action::Report;
+        match change {
+            Change::RemovedDir(path) => {
+                debug!(
+                    "Directory {} was removed, leaving its thumbnails to 'cleanup'",
+                    path.to_string_lossy()
+                );
+            }
+            Change::Removed(path) => self.remove_thumbnails_of(path, action, on_event)?,
+            Change::Moved(from, to) => {
+                // the file moved to `to` replaces any file that was there
+                self.remove_thumbnails_of(to, action, on_event)?;
+                for m in self.move_thumbnails(from, to, dry_run)? {
+                    on_event(&WatchEvent::Moved(m));
+                }
+            }
+            Change::MovedDir(from, to) => {
+                if self.recursive {
+                    watcher.add(to)?;
+                }
+                for m in self.relocate(from, to, dry_run)? {
+                    on_event(&WatchEvent::Moved(m));
+                }
+            }
+            Change::NewDir(path) => {
+                if self.recursive {
+                    watcher.add(path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the deleting `action` to the thumbnails of the file at `path`.
+    fn remove_thumbnails_of<F>(&self, path: &Path, action: Action, on_event: &mut F) -> Result<()>
+    where
+        F: FnMut(&WatchEvent),
+    {
+        let mut thumbs = Vec::new();
+        let mut failed = Vec::new();
+        let found = self
+            .thumbnails_of(path)
+            .with_context(|| format!("Failed for {}", path.to_string_lossy()))?;
+        for entry in found {
+            if let Some(th) = entry.to_thumbnail()? {
+                delete_thumbnail(th, action, &mut thumbs, &mut failed, self);
+            }
+        }
+        for f in failed {
+            warn!(
+                "Failed to delete {}: {}",
+                f.thumbnail.thumbnail.to_string_lossy(),
+                f.error
+            );
+        }
+        for t in thumbs {
+            on_event(&WatchEvent::Deleted(t));
+        }
+
+        Ok(())
+    }
+}
+
+struct Watcher {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    recursive: bool,
+    hidden: bool,
+}
+
+impl Watcher {
+    /// Start watching the directory at `path`, and its subdirectories if recursive.
+    fn add(&mut self, path: &Path) -> Result<()> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::CREATE
+            | WatchMask::ONLYDIR;
+
+        let mut walk = WalkDir::new(path);
+        if !self.recursive {
+            walk = walk.max_depth(0);
+        }
+        let hidden = self.hidden;
+        for entry in walk
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || hidden || !is_hidden_unix(e.file_name()))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir())
+        {
+            let wd = self
+                .inotify
+                .add_watch(entry.path(), mask)
+                .with_context(|| format!("Failed to watch {}", entry.path().to_string_lossy()))?;
+            debug!("Watching {}", entry.path().to_string_lossy());
+            self.dirs.insert(wd, entry.into_path());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_url;
+    use crate::testutil::{thumbnail_uri, thumbnailed_file, TempDir};
+    use std::fs::{create_dir_all, rename, write};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Watch the directory `files` in a thread, with the cache `cache`. Returns
+    /// once watching has started.
+    fn watch(cache: &Path, files: &Path) -> Receiver<WatchEvent> {
+        let (tx, rx) = channel();
+        let (dir, paths) = (cache.to_path_buf(), vec![files.to_path_buf()]);
+        // watching never returns, the thread ends with the tests
+        thread::spawn(move || {
+            let un = UnThumbnailer::with_cache_dir(false, false, false, &dir).unwrap();
+            let _ = un.watch(&paths, false, |e| {
+                let _ = tx.send(match e {
+                    WatchEvent::Deleted(t) => WatchEvent::Deleted(t.clone()),
+                    WatchEvent::Moved(m) => WatchEvent::Moved(m.clone()),
+                });
+            });
+        });
+
+        // write to a file until the deletion of its thumbnail shows up
+        let probe = files.join("probe");
+        for _ in 0..100 {
+            thumbnailed_file(cache, &probe);
+            if rx.recv_timeout(Duration::from_millis(50)).is_ok() {
+                return rx;
+            }
+        }
+        panic!("Failed to watch {}", files.to_string_lossy());
+    }
+
+    /// Wait for the first event about `file`, ignoring the probe of `watch`.
+    fn event_for(rx: &Receiver<WatchEvent>, file: &Path) -> WatchEvent {
+        loop {
+            let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            let about = match &event {
+                WatchEvent::Deleted(t) => &t.file,
+                WatchEvent::Moved(m) => &m.to.file,
+            };
+            if about == file {
+                return event;
+            }
+        }
+    }
+
+    fn setup(name: &str) -> (TempDir, PathBuf, PathBuf) {
+        let dir = TempDir::new(name);
+        let cache = dir.path().canonicalize().unwrap().join("cache");
+        let files = cache.with_file_name("files");
+        create_dir_all(&files).unwrap();
+        (dir, cache, files)
+    }
+
+    #[test]
+    fn delete_thumbnails_of_written_files() {
+        let (_dir, cache, files) = setup("watch-write");
+        let file = files.join("file");
+        let thumb = thumbnailed_file(&cache, &file);
+
+        let rx = watch(&cache, &files);
+        write(&file, b"new content").unwrap();
+
+        assert!(matches!(event_for(&rx, &file), WatchEvent::Deleted(_)));
+        assert!(!thumb.exists());
+    }
+
+    #[test]
+    fn delete_thumbnails_of_atomically_saved_files() {
+        let (_dir, cache, files) = setup("watch-save");
+        let file = files.join("file");
+        let thumb = thumbnailed_file(&cache, &file);
+
+        let rx = watch(&cache, &files);
+        // like an editor: write a hidden temporary file, and rename it
+        let tmp = files.join(".file.swp");
+        write(&tmp, b"new content").unwrap();
+        rename(&tmp, &file).unwrap();
+
+        assert!(matches!(event_for(&rx, &file), WatchEvent::Deleted(_)));
+        assert!(!thumb.exists());
+    }
+
+    #[test]
+    fn carry_thumbnails_of_moved_files() {
+        let (_dir, cache, files) = setup("watch-move");
+        let from = files.join("from");
+        let to = files.join("to");
+        let from_thumb = thumbnailed_file(&cache, &from);
+        // replaced by the move
+        let to_thumb = thumbnailed_file(&cache, &to);
+
+        let rx = watch(&cache, &files);
+        rename(&from, &to).unwrap();
+
+        assert!(matches!(event_for(&rx, &to), WatchEvent::Deleted(_)));
+        assert!(matches!(event_for(&rx, &to), WatchEvent::Moved(_)));
+        assert!(!from_thumb.exists());
+        let url = file_url(&to).unwrap();
+        assert_eq!(thumbnail_uri(&to_thumb), Some(url.to_string()));
+    }
+}